@@ -1,7 +1,7 @@
 use bevy::{log::LogSettings, prelude::*, tasks::IoTaskPool};
 use bevy_ggrs::{GGRSPlugin, SessionType};
 use ggrs::{P2PSession, SessionBuilder};
-use log::info;
+use log::{error, info};
 use matchbox_socket::WebRtcSocket;
 
 mod args;
@@ -86,7 +86,13 @@ fn start_matchbox_socket(mut commands: Commands, args: Res<Args>) {
     // The message loop needs to be awaited, or nothing will happen.
     // We do this here using bevy's task system.
     let task_pool = IoTaskPool::get();
-    task_pool.spawn(message_loop).detach();
+    task_pool
+        .spawn(async move {
+            if let Err(e) = message_loop.await {
+                error!("matchbox message loop failed: {}", e);
+            }
+        })
+        .detach();
 
     commands.insert_resource(Some(socket));
 }
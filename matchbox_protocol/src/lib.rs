@@ -0,0 +1,195 @@
+//! The messages exchanged between `matchbox_socket` and `matchbox_server`
+//!
+//! Both sides serialize them as JSON text messages over a websocket.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Version of the protocol described by the types in this crate
+///
+/// Bumped whenever a change would break communication between sockets and
+/// servers built against different versions.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// How often sockets send a [`PeerRequest::KeepAlive`]
+///
+/// The server may evict peers that miss a few of these in a row.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+pub type PeerId = String;
+
+/// Requests go from peer to signalling server
+///
+/// `S` is the signal relayed to other peers, which the server passes on
+/// without looking at it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum PeerRequest<S> {
+    /// Asks the signalling server to relay a signal to another peer
+    Signal {
+        /// The peer the signal is for
+        receiver: PeerId,
+        /// The signal itself
+        data: S,
+    },
+    /// Sent periodically to keep the connection alive
+    KeepAlive,
+}
+
+/// Events go from signalling server to peer
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum PeerEvent<S> {
+    /// The peer's id, assigned by the signalling server when the peer
+    /// connects, always the first event
+    IdAssigned(PeerId),
+    /// The host of the peer's room, only sent in [`Topology::Star`] rooms
+    ///
+    /// Sent right after [`PeerEvent::IdAssigned`], and again when the host
+    /// leaves and one of its clients takes over.
+    HostAssigned(PeerId),
+    /// A new peer joined the room, and should be sent an offer
+    NewPeer(PeerId),
+    /// A peer left the room, and there's no point in connecting to it anymore
+    PeerLeft(PeerId),
+    /// A signal sent by another peer
+    Signal {
+        /// The peer that sent the signal
+        sender: PeerId,
+        /// The signal itself
+        data: S,
+    },
+    /// The signalling server turned the peer away, and is closing the
+    /// connection
+    Error(ServerError),
+}
+
+/// How the peers in a room connect to each other
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Topology {
+    /// Every peer connects to every other peer
+    #[default]
+    Mesh,
+    /// Only the host, the room's first peer, connects to the other peers
+    Star,
+}
+
+/// Reasons for the signalling server to turn a peer away
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ServerError {
+    /// The room already has as many peers as it allows
+    RoomFull,
+    /// The peer was built against a different [`PROTOCOL_VERSION`] than the
+    /// server
+    VersionMismatch {
+        /// The version the server speaks
+        server: u32,
+    },
+}
+
+/// Signals exchanged between peers in order to establish a connection
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum PeerSignal {
+    /// A serialized ICE candidate
+    IceCandidate(String),
+    /// An SDP offer
+    Offer(String),
+    /// An SDP answer
+    Answer(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{de::DeserializeOwned, Serialize};
+    use std::fmt::Debug;
+
+    use crate::{PeerEvent, PeerRequest, PeerSignal, ServerError, Topology};
+
+    fn round_trip<T: Serialize + DeserializeOwned + PartialEq + Debug>(value: T) {
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(serde_json::from_str::<T>(&json).unwrap(), value);
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        round_trip(PeerRequest::Signal {
+            receiver: "peer".to_string(),
+            data: PeerSignal::Offer("sdp".to_string()),
+        });
+        round_trip(PeerRequest::<PeerSignal>::KeepAlive);
+    }
+
+    #[test]
+    fn events_round_trip() {
+        round_trip(PeerEvent::<PeerSignal>::IdAssigned("me".to_string()));
+        round_trip(PeerEvent::<PeerSignal>::HostAssigned("host".to_string()));
+        round_trip(PeerEvent::<PeerSignal>::NewPeer("peer".to_string()));
+        round_trip(PeerEvent::<PeerSignal>::PeerLeft("peer".to_string()));
+        round_trip(PeerEvent::Signal {
+            sender: "peer".to_string(),
+            data: PeerSignal::IceCandidate("candidate".to_string()),
+        });
+        round_trip(PeerEvent::<PeerSignal>::Error(ServerError::RoomFull));
+        round_trip(PeerEvent::<PeerSignal>::Error(
+            ServerError::VersionMismatch { server: 3 },
+        ));
+    }
+
+    #[test]
+    fn signals_round_trip() {
+        round_trip(PeerSignal::IceCandidate("candidate".to_string()));
+        round_trip(PeerSignal::Offer("offer".to_string()));
+        round_trip(PeerSignal::Answer("answer".to_string()));
+    }
+
+    /// The server relays signals without knowing their type, so it must be
+    /// able to read and write them as arbitrary JSON
+    #[test]
+    fn opaque_signals() {
+        let request = PeerRequest::Signal {
+            receiver: "peer".to_string(),
+            data: PeerSignal::Answer("sdp".to_string()),
+        };
+        let json = serde_json::to_string(&request).unwrap();
+        let opaque: PeerRequest<serde_json::Value> = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&opaque).unwrap(), json);
+    }
+
+    /// Changing any of these breaks compatibility, and requires bumping
+    /// [`crate::PROTOCOL_VERSION`]
+    #[test]
+    fn wire_format() {
+        assert_eq!(
+            serde_json::to_string(&PeerRequest::Signal {
+                receiver: "b".to_string(),
+                data: PeerSignal::Offer("sdp".to_string()),
+            })
+            .unwrap(),
+            r#"{"Signal":{"receiver":"b","data":{"Offer":"sdp"}}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&PeerRequest::<PeerSignal>::KeepAlive).unwrap(),
+            r#""KeepAlive""#
+        );
+        assert_eq!(
+            serde_json::to_string(&PeerEvent::<PeerSignal>::IdAssigned("a".to_string())).unwrap(),
+            r#"{"IdAssigned":"a"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&PeerEvent::<PeerSignal>::HostAssigned("a".to_string())).unwrap(),
+            r#"{"HostAssigned":"a"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&PeerEvent::<PeerSignal>::Error(ServerError::RoomFull)).unwrap(),
+            r#"{"Error":"RoomFull"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&PeerEvent::<PeerSignal>::Error(
+                ServerError::VersionMismatch { server: 3 }
+            ))
+            .unwrap(),
+            r#"{"Error":{"VersionMismatch":{"server":3}}}"#
+        );
+        // Also the value of the `topology` query parameter
+        assert_eq!(serde_json::to_string(&Topology::Star).unwrap(), r#""star""#);
+    }
+}
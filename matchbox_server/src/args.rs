@@ -1,5 +1,9 @@
 use clap::Parser;
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf};
+
+use matchbox_server::{
+    DEFAULT_MAX_MISSED_KEEP_ALIVES, DEFAULT_MAX_ROOM_SIZE, DEFAULT_RECONNECT_GRACE_PERIOD,
+};
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -10,4 +14,29 @@ use std::net::SocketAddr;
 pub struct Args {
     #[clap(default_value = "0.0.0.0:3536", env)]
     pub host: SocketAddr,
+    /// Maximum number of peers in a room, rooms can ask for fewer with `?max=`
+    #[clap(long, default_value_t = DEFAULT_MAX_ROOM_SIZE, env)]
+    pub max_room_size: usize,
+    /// Disconnect peers that miss more than this many keep-alives in a row
+    #[clap(long, default_value_t = DEFAULT_MAX_MISSED_KEEP_ALIVES, env)]
+    pub max_missed_keep_alives: u32,
+    /// Seconds to wait for a peer whose connection dropped to reconnect,
+    /// before telling the others it left
+    #[clap(long, default_value_t = DEFAULT_RECONNECT_GRACE_PERIOD.as_secs(), env)]
+    pub reconnect_grace_period: u64,
+    /// Require peers to present a JSON web token signed with this HS256 secret
+    #[clap(long, env, conflicts_with = "jwt_public_key")]
+    pub jwt_secret: Option<String>,
+    /// Require peers to present a JSON web token signed with RS256, verified
+    /// with the public key in this PEM file
+    #[clap(long, env)]
+    pub jwt_public_key: Option<PathBuf>,
+    /// Use the subject (`sub`) of the token as the peer id, instead of a
+    /// random one
+    #[clap(long, env)]
+    pub jwt_bind_subject: bool,
+    /// Serve Prometheus metrics at `/metrics` on this address, e.g.
+    /// `127.0.0.1:9090`. Metrics aren't served unless this is set
+    #[clap(long, env)]
+    pub metrics_addr: Option<SocketAddr>,
 }
@@ -0,0 +1,334 @@
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::sync::Arc;
+use warp::{Filter, Rejection};
+
+use matchbox_protocol::PeerId;
+
+/// Validates the JSON web tokens peers present when connecting
+pub(crate) struct Auth {
+    key: DecodingKey,
+    validation: Validation,
+    /// Whether peers get the subject of their token as their id
+    bind_subject: bool,
+}
+
+/// The claims we care about, besides the standard `exp`
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: Option<String>,
+    rooms: Option<Vec<String>>,
+}
+
+/// What a peer is allowed to do, according to its token
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Grant {
+    /// The id the peer gets instead of a random one
+    pub peer_id: Option<PeerId>,
+    /// Ids of the rooms the peer may join, any room if `None`
+    pub rooms: Option<Vec<String>>,
+}
+
+impl Grant {
+    pub fn allows_room(&self, room_id: &str) -> bool {
+        match &self.rooms {
+            Some(rooms) => rooms.iter().any(|room| room == room_id),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum AuthError {
+    #[error("no token")]
+    MissingToken,
+    #[error("invalid token: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+}
+
+impl Auth {
+    pub fn new(key: DecodingKey, algorithm: Algorithm, bind_subject: bool) -> Self {
+        let mut validation = Validation::new(algorithm);
+        if bind_subject {
+            validation.set_required_spec_claims(&["exp", "sub"]);
+        }
+        Self {
+            key,
+            validation,
+            bind_subject,
+        }
+    }
+
+    pub fn validate(&self, token: Option<&str>) -> Result<Grant, AuthError> {
+        let token = token.ok_or(AuthError::MissingToken)?;
+        let claims = jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation)?.claims;
+        Ok(Grant {
+            peer_id: if self.bind_subject { claims.sub } else { None },
+            rooms: claims.rooms,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenParam {
+    token: Option<String>,
+}
+
+/// Validates the token in the `token` query parameter, or else the
+/// `Authorization: Bearer` header
+///
+/// Everything is allowed if authentication is disabled.
+pub(crate) fn with_grant(
+    auth: Option<Arc<Auth>>,
+) -> impl Filter<Extract = (Result<Grant, AuthError>,), Error = Rejection> + Clone {
+    warp::query::<TokenParam>()
+        .and(warp::header::optional::<String>("authorization"))
+        .map(move |param: TokenParam, header: Option<String>| {
+            let auth = match &auth {
+                Some(auth) => auth,
+                None => return Ok(Grant::default()),
+            };
+            let token = param.token.or_else(|| {
+                header.and_then(|header| header.strip_prefix("Bearer ").map(str::to_string))
+            });
+            auth.validate(token.as_deref())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::lock::Mutex;
+    use jsonwebtoken::{get_current_timestamp, Algorithm, DecodingKey, EncodingKey, Header};
+    use serde_json::json;
+    use std::{sync::Arc, time::Duration};
+    use warp::{http::StatusCode, test::WsClient, Filter, Rejection, Reply};
+
+    use matchbox_protocol::PeerEvent;
+
+    use crate::{auth::Auth, signaling::State};
+
+    const SECRET: &[u8] = b"secret";
+
+    fn api(bind_subject: bool) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        crate::signaling::ws_filter(
+            Arc::new(Mutex::new(State::default())),
+            Some(Arc::new(Auth::new(
+                DecodingKey::from_secret(SECRET),
+                Algorithm::HS256,
+                bind_subject,
+            ))),
+        )
+    }
+
+    fn token(claims: serde_json::Value) -> String {
+        let mut claims = claims;
+        claims["exp"] = json!(get_current_timestamp() + 60);
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    /// Status of a websocket upgrade request, without completing the upgrade
+    async fn upgrade_status(
+        api: &(impl Filter<Extract = (impl Reply,), Error = Rejection> + 'static),
+        path: &str,
+        authorization: Option<&str>,
+    ) -> StatusCode {
+        let mut request = warp::test::request()
+            .path(path)
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==");
+        if let Some(authorization) = authorization {
+            request = request.header("authorization", authorization);
+        }
+        request.reply(api).await.status()
+    }
+
+    async fn recv_peer_event(client: &mut WsClient) -> PeerEvent<serde_json::Value> {
+        let message = client.recv().await.unwrap();
+        serde_json::from_str(message.to_str().unwrap()).unwrap()
+    }
+
+    async fn recv_id(client: &mut WsClient) -> String {
+        match recv_peer_event(client).await {
+            PeerEvent::IdAssigned(id) => id,
+            event => panic!("expected IdAssigned, got {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_token() {
+        let status = upgrade_status(&api(false), "/room_a", None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn invalid_token() {
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &json!({ "exp": get_current_timestamp() + 60 }),
+            &EncodingKey::from_secret(b"wrong secret"),
+        )
+        .unwrap();
+        let path = format!("/room_a?token={token}");
+        let status = upgrade_status(&api(false), &path, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn expired_token() {
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &json!({ "exp": get_current_timestamp() - 600 }),
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap();
+        let path = format!("/room_a?token={token}");
+        let status = upgrade_status(&api(false), &path, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn token_in_query() {
+        let path = format!("/room_a?next=2&token={}", token(json!({})));
+        let status = upgrade_status(&api(false), &path, None).await;
+        assert_eq!(status, StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[tokio::test]
+    async fn token_in_header() {
+        let header = format!("Bearer {}", token(json!({})));
+        let status = upgrade_status(&api(false), "/room_a", Some(&header)).await;
+        assert_eq!(status, StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[tokio::test]
+    async fn allowed_rooms() {
+        let api = api(false);
+        let token = token(json!({ "rooms": ["room_a"] }));
+
+        let path = format!("/room_a?token={token}");
+        let status = upgrade_status(&api, &path, None).await;
+        assert_eq!(status, StatusCode::SWITCHING_PROTOCOLS);
+
+        let path = format!("/room_b?token={token}");
+        let status = upgrade_status(&api, &path, None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn bind_subject() {
+        let api = api(true);
+
+        let token_without_subject = token(json!({}));
+        let path = format!("/room_a?token={token_without_subject}");
+        let status = upgrade_status(&api, &path, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let path = format!("/room_a?token={}", token(json!({ "sub": "alice" })));
+        let mut client = warp::test::ws()
+            .path(&path)
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        assert_eq!(recv_id(&mut client).await, "alice");
+
+        // Only one peer can have the id at a time, the newest connection wins
+        let mut new_client = warp::test::ws()
+            .path(&path)
+            .handshake(api)
+            .await
+            .expect("handshake");
+        assert_eq!(recv_id(&mut new_client).await, "alice");
+        client.recv_closed().await.expect("closed");
+    }
+
+    #[tokio::test]
+    async fn bind_subject_concurrent() {
+        let api = api(true);
+        let path = format!("/room_a?token={}", token(json!({ "sub": "alice" })));
+
+        let (client_a, client_b) = tokio::join!(
+            warp::test::ws().path(&path).handshake(api.clone()),
+            warp::test::ws().path(&path).handshake(api.clone()),
+        );
+        let mut client_a = client_a.expect("handshake");
+        let mut client_b = client_b.expect("handshake");
+        assert_eq!(recv_id(&mut client_a).await, "alice");
+        assert_eq!(recv_id(&mut client_b).await, "alice");
+
+        // One of them replaced the other
+        let mut client = tokio::select! {
+            closed = client_a.recv_closed() => {
+                closed.expect("closed");
+                client_b
+            }
+            closed = client_b.recv_closed() => {
+                closed.expect("closed");
+                client_a
+            }
+        };
+
+        // Leaving, the replaced connection doesn't take the remaining one with it
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut client_c = warp::test::ws()
+            .path(&format!("/room_a?token={}", token(json!({ "sub": "bob" }))))
+            .handshake(api)
+            .await
+            .expect("handshake");
+        assert_eq!(recv_id(&mut client_c).await, "bob");
+        assert_eq!(
+            recv_peer_event(&mut client).await,
+            PeerEvent::NewPeer("bob".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn bind_subject_reconnect_while_stale() {
+        let api = api(true);
+        let alice = format!("/room_a?token={}", token(json!({ "sub": "alice" })));
+        let bob = format!("/room_a?token={}", token(json!({ "sub": "bob" })));
+
+        let mut client_bob = warp::test::ws()
+            .path(&bob)
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        recv_id(&mut client_bob).await;
+
+        // Stays connected, but is never heard from again
+        let mut stale_alice = warp::test::ws()
+            .path(&alice)
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        recv_id(&mut stale_alice).await;
+        assert_eq!(
+            recv_peer_event(&mut client_bob).await,
+            PeerEvent::NewPeer("alice".to_string())
+        );
+
+        let mut client_alice = warp::test::ws()
+            .path(&alice)
+            .handshake(api)
+            .await
+            .expect("handshake");
+        assert_eq!(recv_id(&mut client_alice).await, "alice");
+        stale_alice.recv_closed().await.expect("closed");
+
+        // Bob connects to the new alice from scratch
+        assert_eq!(
+            recv_peer_event(&mut client_bob).await,
+            PeerEvent::PeerLeft("alice".to_string())
+        );
+        assert_eq!(
+            recv_peer_event(&mut client_bob).await,
+            PeerEvent::NewPeer("alice".to_string())
+        );
+    }
+}
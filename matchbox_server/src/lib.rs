@@ -0,0 +1,30 @@
+//! A signalling server for `matchbox_socket`
+//!
+//! Run the `matchbox_server` binary, or mount a [`SignalingServer`] in your
+//! own warp app:
+//!
+//! ```no_run
+//! use matchbox_server::SignalingServer;
+//! use warp::Filter;
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let server = SignalingServer::builder().max_room_size(4).build();
+//!     let routes = warp::path("matchbox").and(server.filter());
+//!     warp::serve(routes).run(([0, 0, 0, 0], 3536)).await;
+//! }
+//! ```
+
+mod auth;
+mod metrics;
+mod rooms;
+mod server;
+mod signaling;
+
+pub use jsonwebtoken;
+pub use matchbox_protocol::{self, PeerId, Topology, KEEP_ALIVE_INTERVAL, PROTOCOL_VERSION};
+pub use rooms::RoomInfo;
+pub use server::{SignalingServer, SignalingServerBuilder};
+pub use signaling::{
+    DEFAULT_MAX_MISSED_KEEP_ALIVES, DEFAULT_MAX_ROOM_SIZE, DEFAULT_RECONNECT_GRACE_PERIOD,
+};
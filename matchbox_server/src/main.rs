@@ -1,13 +1,12 @@
 use clap::Parser;
 use log::info;
-use std::env;
+use matchbox_server::{SignalingServer, KEEP_ALIVE_INTERVAL, PROTOCOL_VERSION};
+use std::{env, fs, time::Duration};
 use warp::{http::StatusCode, hyper::Method, Filter, Rejection, Reply};
 
 pub use args::Args;
-pub use signaling::matchbox::PeerId;
 
 mod args;
-mod signaling;
 
 #[tokio::main]
 async fn main() {
@@ -53,13 +52,32 @@ async fn main() {
     //     .allow_any_origin()
     //     .allow_methods(&[Method::GET]);
 
-    let routes = health_route
-        .or(signaling::ws_filter(Default::default()))
-        .with(cors)
-        .with(log);
+    let mut server = SignalingServer::builder()
+        .max_room_size(args.max_room_size)
+        .idle_timeout(KEEP_ALIVE_INTERVAL * (args.max_missed_keep_alives + 1))
+        .reconnect_grace_period(Duration::from_secs(args.reconnect_grace_period))
+        .jwt_bind_subject(args.jwt_bind_subject);
+    if let Some(secret) = &args.jwt_secret {
+        server = server.jwt_hs256(secret.as_bytes());
+    } else if let Some(path) = &args.jwt_public_key {
+        let pem = fs::read(path).expect("couldn't read jwt public key");
+        server = server.jwt_rs256(&pem).expect("invalid jwt public key");
+    }
+    if args.jwt_secret.is_some() || args.jwt_public_key.is_some() {
+        info!("Peers need a valid token to connect");
+    }
+    let server = server.build();
+
+    if let Some(addr) = args.metrics_addr {
+        // Metrics aren't authenticated, so they get a port of their own
+        info!("Serving metrics at {addr}");
+        tokio::spawn(warp::serve(server.metrics_filter()).run(addr));
+    }
+
+    let routes = health_route.or(server.filter()).with(cors).with(log);
 
     info!(
-        "Starting matchbox signaling server at port {}",
+        "Starting matchbox signaling server at port {}, protocol version {PROTOCOL_VERSION}",
         args.host.port()
     );
     warp::serve(routes).run(args.host).await;
@@ -0,0 +1,198 @@
+use futures::lock::Mutex;
+use prometheus::{core::Collector, Encoder, IntCounter, IntGauge, Registry, TextEncoder};
+use std::sync::Arc;
+use warp::{Filter, Rejection, Reply};
+
+use crate::signaling::{with_state, State};
+
+/// Telemetry about the signalling server, served at `GET /metrics` in the
+/// Prometheus text format
+///
+/// Counters are updated as things happen, gauges when the metrics are
+/// scraped.
+#[derive(Clone)]
+pub(crate) struct Metrics {
+    registry: Registry,
+    /// Number of connected peers
+    pub clients: IntGauge,
+    /// Number of rooms with peers waiting in them
+    pub rooms: IntGauge,
+    /// Peers that joined a room
+    pub joins: IntCounter,
+    /// Signals relayed from one peer to another
+    pub signals: IntCounter,
+    /// Size of the relayed signals
+    pub signal_bytes: IntCounter,
+    /// Requests from peers that couldn't be parsed
+    pub parse_errors: IntCounter,
+    /// Rooms with `next` that filled up and were matched
+    pub matches: IntCounter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let registry = Registry::new_custom(Some("matchbox".to_string()), None)
+            .expect("valid registry prefix");
+        Metrics {
+            clients: register(
+                &registry,
+                IntGauge::new("clients", "Number of connected peers"),
+            ),
+            rooms: register(
+                &registry,
+                IntGauge::new("rooms", "Number of rooms with peers in them"),
+            ),
+            joins: register(
+                &registry,
+                IntCounter::new("joins_total", "Peers that joined a room"),
+            ),
+            signals: register(
+                &registry,
+                IntCounter::new("signals_total", "Signals relayed between peers"),
+            ),
+            signal_bytes: register(
+                &registry,
+                IntCounter::new(
+                    "signal_bytes_total",
+                    "Bytes of signals relayed between peers",
+                ),
+            ),
+            parse_errors: register(
+                &registry,
+                IntCounter::new(
+                    "parse_errors_total",
+                    "Requests from peers that couldn't be parsed",
+                ),
+            ),
+            matches: register(
+                &registry,
+                IntCounter::new(
+                    "matches_total",
+                    "Rooms with next that filled up and were matched",
+                ),
+            ),
+            registry,
+        }
+    }
+}
+
+fn register<M: Collector + Clone + 'static>(
+    registry: &Registry,
+    metric: prometheus::Result<M>,
+) -> M {
+    let metric = metric.expect("valid metric");
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("metric registered once");
+    metric
+}
+
+impl Metrics {
+    fn encode(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("error encoding metrics");
+        String::from_utf8(buffer).expect("metrics are utf-8")
+    }
+}
+
+pub(crate) fn metrics_filter(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .and(with_state(state))
+        .and_then(metrics_handler)
+}
+
+async fn metrics_handler(state: Arc<Mutex<State>>) -> Result<impl Reply, Rejection> {
+    let metrics = state.lock().await.update_metrics();
+    Ok(metrics.encode())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::lock::Mutex;
+    use std::sync::Arc;
+    use warp::{http::StatusCode, test::WsClient, Filter, Rejection, Reply};
+
+    use matchbox_protocol::{PeerEvent, PeerId};
+
+    use crate::signaling::State;
+
+    fn api() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        let state = Arc::new(Mutex::new(State::default()));
+        crate::signaling::ws_filter(state.clone(), None).or(super::metrics_filter(state))
+    }
+
+    /// Returns the client along with its id
+    async fn join(
+        api: impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static,
+        path: &str,
+    ) -> (WsClient, PeerId) {
+        let mut client = warp::test::ws()
+            .path(path)
+            .handshake(api)
+            .await
+            .expect("handshake");
+        let message = client.recv().await.expect("id");
+        match serde_json::from_str(message.to_str().unwrap()).unwrap() {
+            PeerEvent::<serde_json::Value>::IdAssigned(id) => (client, id),
+            event => panic!("expected IdAssigned, got {:?}", event),
+        }
+    }
+
+    async fn metric(
+        api: &(impl Filter<Extract = (impl Reply,), Error = Rejection> + 'static),
+        name: &str,
+    ) -> String {
+        let response = warp::test::request().path("/metrics").reply(api).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        body.lines()
+            .find_map(|line| line.strip_prefix(&format!("matchbox_{name} ")))
+            .unwrap_or_else(|| panic!("metric {} missing from:\n{}", name, body))
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn clients_and_rooms() {
+        let api = api();
+        let _a = join(api.clone(), "/room_a").await;
+        let _b = join(api.clone(), "/room_a").await;
+        let _c = join(api.clone(), "/room_b").await;
+
+        assert_eq!(metric(&api, "clients").await, "3");
+        assert_eq!(metric(&api, "rooms").await, "2");
+        assert_eq!(metric(&api, "joins_total").await, "3");
+    }
+
+    #[tokio::test]
+    async fn matches() {
+        let api = api();
+        let _a = join(api.clone(), "/room_a?next=2").await;
+        assert_eq!(metric(&api, "matches_total").await, "0");
+        let _b = join(api.clone(), "/room_a?next=2").await;
+        assert_eq!(metric(&api, "matches_total").await, "1");
+        assert_eq!(metric(&api, "rooms").await, "0");
+    }
+
+    #[tokio::test]
+    async fn signals_and_parse_errors() {
+        let api = api();
+        let (mut a, _) = join(api.clone(), "/room_a").await;
+        let (mut b, id_b) = join(api.clone(), "/room_a").await;
+
+        a.send_text("not a request").await;
+        let signal = format!(r#"{{"Signal": {{"receiver": "{id_b}", "data": "hello"}}}}"#);
+        a.send_text(signal).await;
+        // Requests are handled in order, so once the signal arrives, the
+        // invalid request has been handled too
+        b.recv().await.expect("signal");
+
+        assert_eq!(metric(&api, "signals_total").await, "1");
+        assert_ne!(metric(&api, "signal_bytes_total").await, "0");
+        assert_eq!(metric(&api, "parse_errors_total").await, "1");
+    }
+}
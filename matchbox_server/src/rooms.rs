@@ -0,0 +1,148 @@
+use futures::lock::Mutex;
+use matchbox_protocol::Topology;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use crate::signaling::{with_state, State};
+
+/// A public room, as listed by `GET /rooms`
+///
+/// Join it with its id, and `next` if it has one. `max` and `topology` are
+/// set by the peer that created the room.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RoomInfo {
+    pub id: String,
+    /// Number of peers currently in the room
+    pub peers: usize,
+    pub next: Option<usize>,
+    pub max: Option<usize>,
+    pub topology: Topology,
+    /// When the room was created, in seconds since the unix epoch
+    pub created_at: u64,
+}
+
+pub(crate) fn rooms_filter(
+    state: Arc<Mutex<State>>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list = warp::path!("rooms")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and_then(list_rooms_handler);
+    let get = warp::path!("rooms" / String)
+        .and(warp::get())
+        .and(with_state(state))
+        .and_then(get_room_handler);
+    list.or(get)
+}
+
+async fn list_rooms_handler(state: Arc<Mutex<State>>) -> Result<impl Reply, Rejection> {
+    let rooms = state.lock().await.public_rooms();
+    Ok(warp::reply::json(&rooms))
+}
+
+/// Peers matching in groups of different sizes (`next`) under the same id
+/// are in separate rooms, in which case this returns the oldest one
+async fn get_room_handler(id: String, state: Arc<Mutex<State>>) -> Result<impl Reply, Rejection> {
+    let room = state
+        .lock()
+        .await
+        .public_rooms()
+        .into_iter()
+        .find(|room| room.id == id);
+    // Rejecting would let the websocket filter's rejection take precedence
+    Ok(match room {
+        Some(room) => warp::reply::json(&room).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::lock::Mutex;
+    use std::sync::Arc;
+    use warp::{http::StatusCode, test::WsClient, Filter, Rejection, Reply};
+
+    use matchbox_protocol::Topology;
+
+    use crate::{rooms::RoomInfo, signaling::State};
+
+    fn api() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        let state = Arc::new(Mutex::new(State::default()));
+        crate::signaling::ws_filter(state.clone(), None).or(super::rooms_filter(state))
+    }
+
+    async fn join(
+        api: impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static,
+        path: &str,
+    ) -> WsClient {
+        let mut client = warp::test::ws()
+            .path(path)
+            .handshake(api)
+            .await
+            .expect("handshake");
+        // Wait for the id, so we know the peer has joined
+        client.recv().await.expect("id");
+        client
+    }
+
+    #[tokio::test]
+    async fn list_public_rooms() {
+        let api = api();
+        let _a = join(api.clone(), "/public_room?public=true").await;
+        let _b = join(api.clone(), "/public_room?public=true").await;
+        let _c = join(api.clone(), "/paired_room?next=2&public=true").await;
+        let _d = join(api.clone(), "/private_room").await;
+
+        let response = warp::test::request().path("/rooms").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let rooms: Vec<RoomInfo> = serde_json::from_slice(response.body()).unwrap();
+        let mut rooms: Vec<_> = rooms
+            .into_iter()
+            .map(|room| (room.id, room.peers, room.next, room.max))
+            .collect();
+        rooms.sort();
+        assert_eq!(
+            rooms,
+            vec![
+                ("paired_room".to_string(), 1, Some(2), None),
+                ("public_room".to_string(), 2, None, Some(16)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn get_room() {
+        let api = api();
+        let _a = join(api.clone(), "/public_room?public=true").await;
+        let _b = join(api.clone(), "/private_room").await;
+
+        let response = warp::test::request()
+            .path("/rooms/public_room")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let room: RoomInfo = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(room.peers, 1);
+
+        let response = warp::test::request()
+            .path("/rooms/private_room")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn join_listed_room_by_id() {
+        let api = api();
+        let _a = join(api.clone(), "/lobby?public=true&max=4&topology=star").await;
+        let _b = join(api.clone(), "/lobby").await;
+
+        let response = warp::test::request().path("/rooms/lobby").reply(&api).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let room: RoomInfo = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(room.peers, 2);
+        assert_eq!(room.max, Some(4));
+        assert_eq!(room.topology, Topology::Star);
+    }
+}
@@ -0,0 +1,234 @@
+use futures::lock::Mutex;
+use jsonwebtoken::{Algorithm, DecodingKey};
+use matchbox_protocol::{PeerId, KEEP_ALIVE_INTERVAL};
+use std::{sync::Arc, time::Duration};
+use warp::{Filter, Rejection, Reply};
+
+use crate::{
+    auth::Auth,
+    metrics, rooms,
+    rooms::RoomInfo,
+    signaling::{
+        self, Hooks, State, DEFAULT_MAX_MISSED_KEEP_ALIVES, DEFAULT_MAX_ROOM_SIZE,
+        DEFAULT_RECONNECT_GRACE_PERIOD,
+    },
+};
+
+/// A matchbox signalling server, to serve on its own or mount among the
+/// routes of an existing warp app
+///
+/// All filters created from the same server share its rooms and peers.
+/// Prefix them to serve them under a path, e.g.
+/// `warp::path("matchbox").and(server.filter())`. To use them with another
+/// framework, turn them into a tower service with [`warp::service`].
+#[derive(Clone)]
+pub struct SignalingServer {
+    state: Arc<Mutex<State>>,
+    auth: Option<Arc<Auth>>,
+}
+
+impl SignalingServer {
+    pub fn builder() -> SignalingServerBuilder {
+        SignalingServerBuilder::default()
+    }
+
+    /// Websocket connections to `/{room_id}`, where peers signal each other
+    pub fn ws_filter(&self) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        signaling::ws_filter(self.state.clone(), self.auth.clone())
+    }
+
+    /// `GET /rooms` and `GET /rooms/{room_id}`, listing public rooms
+    pub fn rooms_filter(&self) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        rooms::rooms_filter(self.state.clone())
+    }
+
+    /// `GET /metrics`, in the Prometheus text format
+    ///
+    /// Not part of [`SignalingServer::filter`], since anyone who can reach it
+    /// can read it, so serve it somewhere only your monitoring can reach,
+    /// e.g. on a separate port.
+    pub fn metrics_filter(
+        &self,
+    ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        metrics::metrics_filter(self.state.clone())
+    }
+
+    /// The routes peers use, i.e. websockets and room listings
+    pub fn filter(&self) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        // Websocket upgrades go first, so rooms can be called "rooms"
+        self.ws_filter().or(self.rooms_filter())
+    }
+
+    /// The rooms that asked to be listed, oldest first
+    pub async fn public_rooms(&self) -> Vec<RoomInfo> {
+        self.state.lock().await.public_rooms()
+    }
+}
+
+/// Configures a [`SignalingServer`]
+pub struct SignalingServerBuilder {
+    max_room_size: usize,
+    idle_timeout: Duration,
+    reconnect_grace_period: Duration,
+    jwt: Option<(DecodingKey, Algorithm)>,
+    jwt_bind_subject: bool,
+    hooks: Hooks,
+}
+
+impl Default for SignalingServerBuilder {
+    fn default() -> Self {
+        SignalingServerBuilder {
+            max_room_size: DEFAULT_MAX_ROOM_SIZE,
+            idle_timeout: KEEP_ALIVE_INTERVAL * (DEFAULT_MAX_MISSED_KEEP_ALIVES + 1),
+            reconnect_grace_period: DEFAULT_RECONNECT_GRACE_PERIOD,
+            jwt: None,
+            jwt_bind_subject: false,
+            hooks: Hooks::default(),
+        }
+    }
+}
+
+impl SignalingServerBuilder {
+    /// Maximum number of peers in a room, rooms can ask for fewer with
+    /// `?max=`
+    pub fn max_room_size(mut self, max_room_size: usize) -> Self {
+        self.max_room_size = max_room_size;
+        self
+    }
+
+    /// Disconnect peers that haven't sent anything for this long
+    ///
+    /// Sockets send a keep-alive every [`KEEP_ALIVE_INTERVAL`], so this
+    /// should be a few times that.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Keep peers whose connection dropped for this long, in case they
+    /// reconnect
+    ///
+    /// Sockets that reconnect in time keep their id, and the other peers
+    /// aren't told they left. Zero removes peers as soon as their connection
+    /// drops.
+    pub fn reconnect_grace_period(mut self, reconnect_grace_period: Duration) -> Self {
+        self.reconnect_grace_period = reconnect_grace_period;
+        self
+    }
+
+    /// Require peers to present a JSON web token signed with HS256 and this
+    /// secret
+    pub fn jwt_hs256(mut self, secret: &[u8]) -> Self {
+        self.jwt = Some((DecodingKey::from_secret(secret), Algorithm::HS256));
+        self
+    }
+
+    /// Require peers to present a JSON web token signed with RS256, verified
+    /// with this PEM encoded public key
+    pub fn jwt_rs256(mut self, pem: &[u8]) -> Result<Self, jsonwebtoken::errors::Error> {
+        self.jwt = Some((DecodingKey::from_rsa_pem(pem)?, Algorithm::RS256));
+        Ok(self)
+    }
+
+    /// Use the subject (`sub`) of the token as the peer id, instead of a
+    /// random one
+    ///
+    /// A new connection with the id of a connected peer replaces it.
+    pub fn jwt_bind_subject(mut self, bind_subject: bool) -> Self {
+        self.jwt_bind_subject = bind_subject;
+        self
+    }
+
+    /// Called with the peer id and room id when a peer joins a room
+    ///
+    /// Hooks are called while the server state is locked, so they should
+    /// return quickly.
+    pub fn on_peer_joined(mut self, hook: impl Fn(&PeerId, &str) + Send + Sync + 'static) -> Self {
+        self.hooks.peer_joined = Some(Arc::new(hook));
+        self
+    }
+
+    /// Called with the peer id when a peer disconnects
+    pub fn on_peer_left(mut self, hook: impl Fn(&PeerId) + Send + Sync + 'static) -> Self {
+        self.hooks.peer_left = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn build(self) -> SignalingServer {
+        let state = State::new(self.max_room_size, self.idle_timeout)
+            .with_reconnect_grace_period(self.reconnect_grace_period)
+            .with_hooks(self.hooks);
+        let jwt_bind_subject = self.jwt_bind_subject;
+        SignalingServer {
+            state: Arc::new(Mutex::new(state)),
+            auth: self
+                .jwt
+                .map(|(key, algorithm)| Arc::new(Auth::new(key, algorithm, jwt_bind_subject))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use tokio::time;
+    use warp::{http::StatusCode, Filter};
+
+    use crate::SignalingServer;
+
+    #[tokio::test]
+    async fn hooks_and_prefix() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let joined = events.clone();
+        let left = events.clone();
+        let server = SignalingServer::builder()
+            .on_peer_joined(move |id, room| joined.lock().unwrap().push(format!("{id} {room}")))
+            .on_peer_left(move |id| left.lock().unwrap().push(format!("{id} left")))
+            .build();
+        let api = warp::path("matchbox").and(server.filter());
+
+        let mut client = warp::test::ws()
+            .path("/matchbox/room_a?public=true")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        client.recv().await.expect("id");
+
+        assert_eq!(server.public_rooms().await.len(), 1);
+        let id = {
+            let events = events.lock().unwrap();
+            assert_eq!(events.len(), 1);
+            events[0]
+                .strip_suffix(" room_a")
+                .expect("room id")
+                .to_string()
+        };
+
+        drop(client);
+        for _ in 0..100 {
+            if events.lock().unwrap().len() > 1 {
+                break;
+            }
+            time::sleep(time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(events.lock().unwrap()[1], format!("{id} left"));
+        assert!(server.public_rooms().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn metrics_not_public() {
+        let server = SignalingServer::builder().build();
+
+        let response = warp::test::request()
+            .path("/metrics")
+            .reply(&server.filter())
+            .await;
+        assert_ne!(response.status(), StatusCode::OK);
+
+        let response = warp::test::request()
+            .path("/metrics")
+            .reply(&server.metrics_filter())
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
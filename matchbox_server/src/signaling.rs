@@ -4,103 +4,445 @@ use std::{
     collections::{HashMap, HashSet},
     convert::Infallible,
     sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use warp::{
+    http::StatusCode,
     ws::{Message, WebSocket},
     Error, Filter, Rejection, Reply,
 };
 
-pub mod matchbox {
-    use serde::{Deserialize, Serialize};
+use matchbox_protocol::{PeerId, ServerError, Topology, KEEP_ALIVE_INTERVAL, PROTOCOL_VERSION};
 
-    pub type PeerId = String;
-
-    /// Requests go from peer to signalling server
-    #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
-    pub enum PeerRequest<S> {
-        Uuid(PeerId),
-        Signal { receiver: PeerId, data: S },
-        KeepAlive,
-    }
-
-    /// Events go from signalling server to peer
-    #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
-    pub enum PeerEvent<S> {
-        NewPeer(PeerId),
-        Signal { sender: PeerId, data: S },
-    }
-}
-use matchbox::*;
+use crate::{
+    auth::{with_grant, Auth, AuthError, Grant},
+    metrics::Metrics,
+    rooms::RoomInfo,
+};
 
-type PeerRequest = matchbox::PeerRequest<serde_json::Value>;
-type PeerEvent = matchbox::PeerEvent<serde_json::Value>;
+// Signals are relayed as they are, no need to understand them
+type PeerRequest = matchbox_protocol::PeerRequest<serde_json::Value>;
+type PeerEvent = matchbox_protocol::PeerEvent<serde_json::Value>;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub(crate) struct RoomId(String);
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct RequestedRoom {
     id: RoomId,
     next: Option<usize>,
+    max: Option<usize>,
+    public: bool,
+    topology: Topology,
+}
+
+impl RequestedRoom {
+    fn key(&self) -> RoomKey {
+        RoomKey {
+            id: self.id.clone(),
+            next: self.next,
+        }
+    }
+}
+
+/// Peers asking for rooms with the same key join the same room
+///
+/// Peers waiting for matches of different sizes aren't matched with each
+/// other, but the other parameters are set by the peer that creates the room.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct RoomKey {
+    id: RoomId,
+    next: Option<usize>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub(crate) struct QueryParam {
     next: Option<usize>,
+    max: Option<usize>,
+    #[serde(default)]
+    public: bool,
+    #[serde(default)]
+    topology: Topology,
+    resume: Option<String>,
+    /// The protocol version the peer speaks, if it says
+    v: Option<u32>,
 }
 
+/// The peers waiting in a room
+pub(crate) struct Room {
+    peers: HashSet<PeerId>,
+    created_at: SystemTime,
+    /// The peer the others connect to, in star rooms
+    host: Option<PeerId>,
+    /// Maximum number of peers, set by the peer that created the room, like
+    /// `public` and `topology`
+    max: Option<usize>,
+    public: bool,
+    topology: Topology,
+}
+
+impl Room {
+    fn new(max: Option<usize>, public: bool, topology: Topology) -> Self {
+        Room {
+            peers: HashSet::new(),
+            created_at: SystemTime::now(),
+            host: None,
+            max,
+            public,
+            topology,
+        }
+    }
+}
+
+/// Maximum number of peers in a room, unless configured otherwise
+pub const DEFAULT_MAX_ROOM_SIZE: usize = 16;
+
+/// Number of keep-alives a peer may miss in a row before it's evicted, unless
+/// configured otherwise
+pub const DEFAULT_MAX_MISSED_KEEP_ALIVES: u32 = 3;
+
+/// How long a peer whose connection dropped is kept around for it to
+/// reconnect, unless configured otherwise
+pub const DEFAULT_RECONNECT_GRACE_PERIOD: Duration = Duration::from_secs(20);
+
+/// Close code sent along with [`ServerError::RoomFull`]
+const ROOM_FULL_CLOSE_CODE: u16 = 4000;
+
+/// Close code sent along with [`ServerError::VersionMismatch`]
+const VERSION_MISMATCH_CLOSE_CODE: u16 = 4001;
+
 pub(crate) struct Peer {
     pub uuid: PeerId,
-    pub room: RequestedRoom,
-    pub sender: tokio::sync::mpsc::UnboundedSender<std::result::Result<Message, warp::Error>>,
+    pub room: RoomKey,
+    pub sender: PeerSender,
+    /// Peers that were told about this peer, or this peer about them
+    pub known_peers: HashSet<PeerId>,
+    /// The host of the peer's room, if it's a star room
+    pub host: Option<PeerId>,
+    /// Secret the peer can reconnect with, to keep its id
+    pub resume_token: Option<String>,
+    /// Messages sent while the peer is disconnected, waiting for it to
+    /// reconnect
+    pub missed: Option<PeerReceiver>,
 }
 
-#[derive(Default)]
 pub(crate) struct State {
     clients: HashMap<PeerId, Peer>,
-    rooms: HashMap<RequestedRoom, HashSet<PeerId>>,
+    rooms: HashMap<RoomKey, Room>,
+    max_room_size: usize,
+    /// Peers we haven't heard from for this long are evicted
+    idle_timeout: Duration,
+    /// Peers whose connection dropped are kept for this long, in case they
+    /// reconnect
+    reconnect_grace_period: Duration,
+    metrics: Metrics,
+    hooks: Hooks,
+}
+
+type PeerSender = mpsc::UnboundedSender<std::result::Result<Message, warp::Error>>;
+type PeerReceiver = mpsc::UnboundedReceiver<std::result::Result<Message, warp::Error>>;
+
+type PeerJoinedHook = Arc<dyn Fn(&PeerId, &str) + Send + Sync>;
+type PeerLeftHook = Arc<dyn Fn(&PeerId) + Send + Sync>;
+
+/// Callbacks into the application embedding the server, called while the
+/// state is locked
+#[derive(Default, Clone)]
+pub(crate) struct Hooks {
+    pub peer_joined: Option<PeerJoinedHook>,
+    pub peer_left: Option<PeerLeftHook>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State::new(
+            DEFAULT_MAX_ROOM_SIZE,
+            KEEP_ALIVE_INTERVAL * (DEFAULT_MAX_MISSED_KEEP_ALIVES + 1),
+        )
+    }
 }
 
 impl State {
-    /// Returns peers already in room
-    fn add_peer(&mut self, peer: Peer) -> Vec<PeerId> {
+    /// Rooms without `next` are limited to `max_room_size` peers, or less if
+    /// they ask for it with `max`
+    ///
+    /// Peers that haven't sent anything, not even a keep-alive, for
+    /// `idle_timeout` are disconnected, so half-open connections don't
+    /// leave ghost peers behind.
+    pub fn new(max_room_size: usize, idle_timeout: Duration) -> Self {
+        State {
+            clients: Default::default(),
+            rooms: Default::default(),
+            max_room_size,
+            idle_timeout,
+            reconnect_grace_period: DEFAULT_RECONNECT_GRACE_PERIOD,
+            metrics: Default::default(),
+            hooks: Default::default(),
+        }
+    }
+
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Peers that reconnect with the same resume token within
+    /// `reconnect_grace_period` keep their id, and the other peers aren't
+    /// told they left
+    pub fn with_reconnect_grace_period(mut self, reconnect_grace_period: Duration) -> Self {
+        self.reconnect_grace_period = reconnect_grace_period;
+        self
+    }
+
+    /// Returns the peers already in the room that should connect to the new
+    /// one, which is only the host in star rooms
+    ///
+    /// The first peer in a room sets its limit, whether it's public and its
+    /// topology. What later peers ask for is ignored.
+    fn add_peer(
+        &mut self,
+        mut peer: Peer,
+        requested_room: &RequestedRoom,
+    ) -> Result<Vec<PeerId>, ServerError> {
         let peer_id = peer.uuid.clone();
         let room = peer.room.clone();
-        self.clients.insert(peer.uuid.clone(), peer);
-        let peers = self.rooms.entry(room.clone()).or_default();
-
-        let ret = peers.iter().cloned().collect();
-        match room.next {
-            None => {
-                peers.insert(peer_id);
-                ret
-            }
-            Some(num_players) => {
-                if peers.len() == num_players - 1 {
-                    peers.clear(); // the room is complete, we can forget about it now
+        // Checked before creating the room, so rejected peers don't leave
+        // empty rooms behind
+        let (max, peer_count) = match self.rooms.get(&room) {
+            Some(entry) => (entry.max, entry.peers.len()),
+            None => (self.room_max(requested_room), 0),
+        };
+        if matches!(max, Some(max) if peer_count >= max) {
+            return Err(ServerError::RoomFull);
+        }
+        let entry = self
+            .rooms
+            .entry(room.clone())
+            .or_insert_with(|| Room::new(max, requested_room.public, requested_room.topology));
+
+        let ret: Vec<PeerId> = match entry.topology {
+            Topology::Mesh => entry.peers.iter().cloned().collect(),
+            Topology::Star => {
+                // The first peer to join hosts the room
+                let host = entry.host.get_or_insert_with(|| peer_id.clone()).clone();
+                peer.host = Some(host.clone());
+                if host == peer_id {
+                    vec![]
                 } else {
-                    peers.insert(peer_id);
+                    vec![host]
                 }
-                ret
             }
+        };
+        let peers = &mut entry.peers;
+        match room.next {
+            Some(num_players) if peers.len() == num_players - 1 => {
+                // the room is complete, we can forget about it now
+                self.rooms.remove(&room);
+                self.metrics.matches.inc();
+            }
+            _ => {
+                peers.insert(peer_id.clone());
+            }
+        }
+
+        for other in &ret {
+            if let Some(other) = self.clients.get_mut(other) {
+                other.known_peers.insert(peer_id.clone());
+            }
+        }
+        peer.known_peers.extend(ret.iter().cloned());
+        if let Some(hook) = &self.hooks.peer_joined {
+            hook(&peer_id, &room.id.0);
+        }
+        self.clients.insert(peer_id, peer);
+        self.metrics.joins.inc();
+        Ok(ret)
+    }
+
+    /// Rooms asking for more than we allow get what we allow, and `next`
+    /// rooms are limited by their size already
+    fn room_max(&self, requested_room: &RequestedRoom) -> Option<usize> {
+        match requested_room.next {
+            Some(_) => None,
+            None => Some(
+                requested_room
+                    .max
+                    .map_or(self.max_room_size, |max| max.min(self.max_room_size)),
+            ),
         }
     }
 
-    fn remove_peer(&mut self, peer_id: &PeerId) {
+    /// Returns the peers that knew about the removed peer
+    fn remove_peer(&mut self, peer_id: &PeerId) -> Vec<PeerId> {
         let peer = self
             .clients
             .remove(peer_id)
             .expect("Couldn't find uuid to remove");
 
-        let room_peers = self.rooms.get_mut(&peer.room);
+        let room = self.rooms.get_mut(&peer.room);
+
+        if let Some(room) = room {
+            room.peers.remove(peer_id);
+            if room.peers.is_empty() {
+                self.rooms.remove(&peer.room);
+            }
+        }
+
+        for other in &peer.known_peers {
+            if let Some(other) = self.clients.get_mut(other) {
+                other.known_peers.remove(peer_id);
+            }
+        }
+        if let Some(hook) = &self.hooks.peer_left {
+            hook(peer_id);
+        }
+        peer.known_peers.into_iter().collect()
+    }
+
+    /// Makes one of the clients of a host that left the new host
+    ///
+    /// Returns the new host, and the other clients it should connect to, or
+    /// `None` if the peer that left wasn't a host with clients.
+    fn replace_host(&mut self, old_host: &PeerId) -> Option<(PeerId, Vec<PeerId>)> {
+        let mut clients: Vec<PeerId> = self
+            .clients
+            .values()
+            .filter(|peer| peer.host.as_ref() == Some(old_host))
+            .map(|peer| peer.uuid.clone())
+            .collect();
+        if clients.is_empty() {
+            return None;
+        }
+        let new_host = clients.swap_remove(0);
+
+        for client in &clients {
+            let client = self.clients.get_mut(client).expect("client exists");
+            client.host = Some(new_host.clone());
+            client.known_peers.insert(new_host.clone());
+        }
+        let host = self.clients.get_mut(&new_host).expect("client exists");
+        host.host = Some(new_host.clone());
+        host.known_peers.extend(clients.iter().cloned());
+        // Rooms with `next` are forgotten once they're complete, and the room
+        // with the same name may be the next group filling up, with its own host
+        if let Some(room) = self
+            .rooms
+            .get_mut(&host.room)
+            .filter(|room| room.host.as_ref() == Some(old_host))
+        {
+            room.host = Some(new_host.clone());
+        }
+        Some((new_host, clients))
+    }
+
+    /// Keeps the messages for a peer whose connection dropped, until it
+    /// reconnects
+    ///
+    /// Returns the sender they go to in the meantime.
+    fn hold_peer(&mut self, peer_id: &PeerId) -> PeerSender {
+        let (sender, missed) = mpsc::unbounded_channel();
+        let peer = self.clients.get_mut(peer_id).expect("peer exists");
+        peer.sender = sender.clone();
+        peer.missed = Some(missed);
+        sender
+    }
+
+    /// Hands the peer with the given resume token over to its new connection
+    ///
+    /// Returns the peer's id, and the messages it missed while it was gone,
+    /// or `None` if there's no such peer in the room.
+    fn resume_peer(
+        &mut self,
+        resume_token: &str,
+        room_id: &RoomId,
+        peer_id: Option<&PeerId>,
+        sender: PeerSender,
+    ) -> Option<(PeerId, Vec<Message>)> {
+        let peer = self.clients.values_mut().find(|peer| {
+            peer.resume_token.as_deref() == Some(resume_token)
+                && &peer.room.id == room_id
+                && (peer_id.is_none() || peer_id == Some(&peer.uuid))
+        })?;
+        // The old connection may still be open, if we haven't noticed it's
+        // gone yet
+        let _ = peer.sender.send(Ok(Message::close()));
+        peer.sender = sender;
+        let mut missed = vec![];
+        if let Some(mut receiver) = peer.missed.take() {
+            while let Ok(Ok(message)) = receiver.try_recv() {
+                missed.push(message);
+            }
+        }
+        Some((peer.uuid.clone(), missed))
+    }
+
+    /// Removes a peer, and tells the peers that knew about it that it's gone
+    fn disconnect_peer(&mut self, peer_id: &PeerId) {
+        let peers = self.remove_peer(peer_id);
 
-        if let Some(room_peers) = room_peers {
-            room_peers.remove(peer_id);
+        let event = Message::text(
+            serde_json::to_string(&PeerEvent::PeerLeft(peer_id.clone()))
+                .expect("error serializing message"),
+        );
+        for other in peers {
+            // Tell everyone who knew about this peer that it's gone
+            info!("{:?} -> {:?}", other, event.to_str().unwrap());
+            self.try_send(&other, event.clone());
+        }
+
+        if let Some((host, clients)) = self.replace_host(peer_id) {
+            let event = Message::text(
+                serde_json::to_string(&PeerEvent::HostAssigned(host.clone()))
+                    .expect("error serializing message"),
+            );
+            for other in clients.iter().chain([&host]) {
+                info!("{:?} -> {:?}", other, event.to_str().unwrap());
+                self.try_send(other, event.clone());
+            }
+            for client in clients {
+                // The new host connects to the clients of the old one
+                let event = Message::text(
+                    serde_json::to_string(&PeerEvent::NewPeer(client))
+                        .expect("error serializing message"),
+                );
+                info!("{:?} -> {:?}", host, event.to_str().unwrap());
+                self.try_send(&host, event);
+            }
         }
     }
 
+    /// Brings the gauges up to date, and returns the metrics to report
+    pub fn update_metrics(&self) -> Metrics {
+        self.metrics.clients.set(self.clients.len() as i64);
+        self.metrics.rooms.set(self.rooms.len() as i64);
+        self.metrics.clone()
+    }
+
+    /// Returns the rooms that asked to be listed, oldest first
+    pub fn public_rooms(&self) -> Vec<RoomInfo> {
+        let mut rooms: Vec<_> = self
+            .rooms
+            .iter()
+            .filter(|(_, room)| room.public)
+            .map(|(key, room)| RoomInfo {
+                id: key.id.0.clone(),
+                peers: room.peers.len(),
+                next: key.next,
+                max: room.max,
+                topology: room.topology,
+                created_at: room
+                    .created_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            })
+            .collect();
+        rooms.sort_by_key(|room| room.created_at);
+        rooms
+    }
+
     fn try_send(&self, id: &PeerId, message: Message) {
         let peer = self.clients.get(id);
         let peer = match peer {
@@ -120,21 +462,20 @@ fn parse_room_id(id: String) -> RoomId {
     RoomId(id)
 }
 
-// I don't understand warp well enough to get rid of this warning
-// This is going to be a hard error in the future, though:
-// https://doc.rust-lang.org/nightly/nightly-rustc/rustc_lint/opaque_hidden_inferred_bound/static.OPAQUE_HIDDEN_INFERRED_BOUND.html
-// The compiler hint tells us to add an impl warp::generic::Tuple bound to the
-// Extract associated type. That type is private, though... I'm hoping that in
-// the meantime someone else will run into similar issues with warp and find
-// solutions for them.
-#[allow(opaque_hidden_inferred_bound)]
 pub(crate) fn ws_filter(
     state: Arc<Mutex<State>>,
-) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    auth: Option<Arc<Auth>>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::ws()
         .and(warp::any())
         .and(warp::path::param().map(parse_room_id))
         .and(warp::query::<QueryParam>().map(parse_room_next))
+        .and(warp::query::<QueryParam>().map(parse_room_max))
+        .and(warp::query::<QueryParam>().map(parse_room_public))
+        .and(warp::query::<QueryParam>().map(parse_room_topology))
+        .and(warp::query::<QueryParam>().map(parse_resume_token))
+        .and(warp::query::<QueryParam>().map(parse_protocol_version))
+        .and(with_grant(auth))
         .and(with_state(state))
         .and_then(ws_handler)
 }
@@ -143,21 +484,75 @@ fn parse_room_next(p: QueryParam) -> Option<usize> {
     p.next
 }
 
-fn with_state(
+/// A room needs room for at least the peer asking for it
+fn parse_room_max(p: QueryParam) -> Option<usize> {
+    p.max.map(|max| max.max(1))
+}
+
+fn parse_room_public(p: QueryParam) -> bool {
+    p.public
+}
+
+fn parse_room_topology(p: QueryParam) -> Topology {
+    p.topology
+}
+
+fn parse_resume_token(p: QueryParam) -> Option<String> {
+    p.resume
+}
+
+fn parse_protocol_version(p: QueryParam) -> Option<u32> {
+    p.v
+}
+
+pub(crate) fn with_state(
     state: Arc<Mutex<State>>,
 ) -> impl Filter<Extract = (Arc<Mutex<State>>,), Error = Infallible> + Clone {
     warp::any().map(move || state.clone())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn ws_handler(
     ws: warp::ws::Ws,
     room_id: RoomId,
     next: Option<usize>,
+    max: Option<usize>,
+    public: bool,
+    topology: Topology,
+    resume_token: Option<String>,
+    version: Option<u32>,
+    grant: Result<Grant, AuthError>,
     state: Arc<Mutex<State>>,
 ) -> std::result::Result<impl Reply, Rejection> {
-    Ok(ws.on_upgrade(move |websocket| {
-        handle_ws(websocket, state, RequestedRoom { id: room_id, next })
-    }))
+    let grant = match grant {
+        Ok(grant) => grant,
+        Err(e) => {
+            warn!("Rejecting peer: {e}");
+            return Ok(StatusCode::UNAUTHORIZED.into_response());
+        }
+    };
+    if !grant.allows_room(&room_id.0) {
+        warn!("Rejecting peer, not allowed in room {:?}", room_id.0);
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    Ok(ws
+        .on_upgrade(move |websocket| {
+            handle_ws(
+                websocket,
+                state,
+                RequestedRoom {
+                    id: room_id,
+                    next,
+                    max,
+                    public,
+                    topology,
+                },
+                grant.peer_id,
+                resume_token,
+                version,
+            )
+        })
+        .into_response())
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -190,20 +585,155 @@ fn parse_request(request: Result<Message, Error>) -> Result<PeerRequest, Request
     Ok(request)
 }
 
-fn spawn_sender_task(
-    sender: SplitSink<WebSocket, Message>,
-) -> mpsc::UnboundedSender<std::result::Result<Message, warp::Error>> {
+/// Tells the peer why it's turned away, and closes the connection
+fn turn_away(sender: &PeerSender, error: ServerError) {
+    let event = Message::text(
+        serde_json::to_string(&PeerEvent::Error(error)).expect("error serializing message"),
+    );
+    let close = match error {
+        ServerError::RoomFull => Message::close_with(ROOM_FULL_CLOSE_CODE, "room is full"),
+        ServerError::VersionMismatch { .. } => {
+            Message::close_with(VERSION_MISMATCH_CLOSE_CODE, "protocol version mismatch")
+        }
+    };
+    // The peer may be gone already, in which case there's nobody to tell
+    let _ = sender.send(Ok(event));
+    let _ = sender.send(Ok(close));
+}
+
+fn spawn_sender_task(sender: SplitSink<WebSocket, Message>) -> PeerSender {
     let (client_sender, receiver) = mpsc::unbounded_channel();
     tokio::task::spawn(UnboundedReceiverStream::new(receiver).forward(sender));
     client_sender
 }
 
-async fn handle_ws(websocket: WebSocket, state: Arc<Mutex<State>>, requested_room: RequestedRoom) {
+async fn handle_ws(
+    websocket: WebSocket,
+    state: Arc<Mutex<State>>,
+    requested_room: RequestedRoom,
+    peer_id: Option<PeerId>,
+    resume_token: Option<String>,
+    version: Option<u32>,
+) {
     let (ws_sender, mut ws_receiver) = websocket.split();
     let sender = spawn_sender_task(ws_sender);
-    let mut peer_uuid = None;
 
-    while let Some(request) = ws_receiver.next().await {
+    // Peers that don't say which version they speak get the benefit of the doubt
+    if let Some(version) = version.filter(|version| *version != PROTOCOL_VERSION) {
+        warn!("Turning away peer speaking protocol version {version}, we speak {PROTOCOL_VERSION}");
+        turn_away(
+            &sender,
+            ServerError::VersionMismatch {
+                server: PROTOCOL_VERSION,
+            },
+        );
+        return;
+    }
+
+    let (peer_uuid, metrics, idle_timeout) = {
+        let mut state = state.lock().await;
+        let resumed = resume_token.as_deref().and_then(|resume_token| {
+            state.resume_peer(
+                resume_token,
+                &requested_room.id,
+                peer_id.as_ref(),
+                sender.clone(),
+            )
+        });
+        let (peer_uuid, peers, missed) = match resumed {
+            Some((peer_uuid, missed)) => {
+                info!("{peer_uuid:?} reconnected");
+                (peer_uuid, vec![], missed)
+            }
+            None => {
+                // The server decides the id, so clients can't impersonate
+                // each other, unless it's bound to their token
+                let peer_uuid = peer_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+                if state.clients.contains_key(&peer_uuid) {
+                    // Most likely the same peer reconnecting before we noticed
+                    // its old connection is gone, so the new connection takes over
+                    warn!("Replacing the existing connection of {peer_uuid:?}");
+                    state.try_send(&peer_uuid, Message::close());
+                    state.disconnect_peer(&peer_uuid);
+                }
+                match state.add_peer(
+                    Peer {
+                        uuid: peer_uuid.clone(),
+                        sender: sender.clone(),
+                        room: requested_room.key(),
+                        known_peers: HashSet::new(),
+                        host: None,
+                        resume_token: resume_token.clone(),
+                        missed: None,
+                    },
+                    &requested_room,
+                ) {
+                    Ok(peers) => (peer_uuid, peers, vec![]),
+                    Err(e) => {
+                        warn!("Turning away peer {peer_uuid:?}: {e:?}");
+                        turn_away(&sender, e);
+                        return;
+                    }
+                }
+            }
+        };
+
+        let event = Message::text(
+            serde_json::to_string(&PeerEvent::IdAssigned(peer_uuid.clone()))
+                .expect("error serializing message"),
+        );
+        info!("{:?} -> {:?}", peer_uuid, event.to_str().unwrap());
+        state.try_send(&peer_uuid, event);
+
+        if let Some(host) = state.clients[&peer_uuid].host.clone() {
+            let event = Message::text(
+                serde_json::to_string(&PeerEvent::HostAssigned(host))
+                    .expect("error serializing message"),
+            );
+            info!("{:?} -> {:?}", peer_uuid, event.to_str().unwrap());
+            state.try_send(&peer_uuid, event);
+        }
+
+        for message in missed {
+            info!(
+                "{:?} -> {:?}",
+                peer_uuid,
+                message.to_str().unwrap_or_default()
+            );
+            state.try_send(&peer_uuid, message);
+        }
+
+        let event = Message::text(
+            serde_json::to_string(&PeerEvent::NewPeer(peer_uuid.clone()))
+                .expect("error serializing message"),
+        );
+
+        for peer_id in peers {
+            // Tell everyone about this new peer
+            info!("{:?} -> {:?}", peer_id, event.to_str().unwrap());
+            state.try_send(&peer_id, event.clone());
+        }
+        (peer_uuid, state.metrics.clone(), state.idle_timeout)
+    };
+
+    // Whether the peer left on purpose, rather than losing its connection
+    let mut closed = false;
+    // Whether we gave up on the peer, which might be a half-open connection
+    // that would otherwise linger in its room for the grace period as well
+    let mut evicted = false;
+
+    loop {
+        let request = match tokio::time::timeout(idle_timeout, ws_receiver.next()).await {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(_) => {
+                warn!("Evicting {peer_uuid:?}, nothing heard for {idle_timeout:?}");
+                // In case it's still there, but too slow
+                let _ = sender.send(Ok(Message::close()));
+                evicted = true;
+                break;
+            }
+        };
         let request = match parse_request(request) {
             Ok(request) => request,
             Err(RequestError::Warp(e)) => {
@@ -214,10 +744,12 @@ async fn handle_ws(websocket: WebSocket, state: Arc<Mutex<State>>, requested_roo
             }
             Err(RequestError::Close) => {
                 info!("Received websocket close from {peer_uuid:?}");
+                closed = true;
                 break;
             }
             Err(e) => {
                 error!("Error untangling request: {:?}", e);
+                metrics.parse_errors.inc();
                 continue;
             }
         };
@@ -225,45 +757,16 @@ async fn handle_ws(websocket: WebSocket, state: Arc<Mutex<State>>, requested_roo
         info!("{:?} <- {:?}", peer_uuid, request);
 
         match request {
-            PeerRequest::Uuid(id) => {
-                if peer_uuid.is_some() {
-                    error!("client set uuid more than once");
-                    continue;
-                }
-                peer_uuid = Some(id.clone());
-
-                let mut state = state.lock().await;
-                let peers = state.add_peer(Peer {
-                    uuid: id.clone(),
-                    sender: sender.clone(),
-                    room: requested_room.clone(),
-                });
-
-                let event = Message::text(
-                    serde_json::to_string(&PeerEvent::NewPeer(id.clone()))
-                        .expect("error serializing message"),
-                );
-
-                for peer_id in peers {
-                    // Tell everyone about this new peer
-                    info!("{:?} -> {:?}", peer_id, event.to_str().unwrap());
-                    state.try_send(&peer_id, event.clone());
-                }
-            }
             PeerRequest::Signal { receiver, data } => {
-                let sender = match peer_uuid.clone() {
-                    Some(sender) => sender,
-                    None => {
-                        error!("client is trying signal before sending uuid");
-                        continue;
-                    }
-                };
+                let sender = peer_uuid.clone();
                 let event = Message::text(
                     serde_json::to_string(&PeerEvent::Signal { sender, data })
                         .expect("error serializing message"),
                 );
                 let state = state.lock().await;
                 if let Some(peer) = state.clients.get(&receiver) {
+                    metrics.signals.inc();
+                    metrics.signal_bytes.inc_by(event.as_bytes().len() as u64);
                     if let Err(e) = peer.sender.send(Ok(event)) {
                         error!("error sending: {:?}", e);
                     }
@@ -275,28 +778,79 @@ async fn handle_ws(websocket: WebSocket, state: Arc<Mutex<State>>, requested_roo
         }
     }
 
-    info!("Removing peer: {:?}", peer_uuid);
-    if let Some(uuid) = peer_uuid {
+    let (held, grace_period) = {
         let mut state = state.lock().await;
-        state.remove_peer(&uuid);
+        if !matches!(state.clients.get(&peer_uuid), Some(peer) if peer.sender.same_channel(&sender))
+        {
+            info!("Connection of {peer_uuid:?} was replaced, not removing the peer");
+            return;
+        }
+        if closed || evicted || resume_token.is_none() || state.reconnect_grace_period.is_zero() {
+            info!("Removing peer: {:?}", peer_uuid);
+            state.disconnect_peer(&peer_uuid);
+            return;
+        }
+        (state.hold_peer(&peer_uuid), state.reconnect_grace_period)
+    };
+
+    info!("Lost connection to {peer_uuid:?}, waiting {grace_period:?} for it to reconnect");
+    tokio::time::sleep(grace_period).await;
+
+    let mut state = state.lock().await;
+    if matches!(state.clients.get(&peer_uuid), Some(peer) if peer.sender.same_channel(&held)) {
+        info!("Removing peer: {:?}, it didn't reconnect", peer_uuid);
+        state.disconnect_peer(&peer_uuid);
     }
 }
 
 #[cfg(test)]
 mod tests {
 
-    use std::time::Duration;
+    use std::{sync::Arc, time::Duration};
 
-    use futures::pin_mut;
+    use futures::{lock::Mutex, pin_mut, StreamExt};
     use tokio::{select, time};
     use warp::{test::WsClient, ws::Message, Filter, Rejection, Reply};
 
-    use crate::signaling::{parse_room_id, parse_room_next, PeerEvent, QueryParam, RoomId};
+    use crate::signaling::{
+        parse_protocol_version, parse_room_id, parse_room_max, parse_room_next, parse_room_public,
+        parse_room_topology, PeerEvent, PeerId, PeerRequest, QueryParam, RoomId, ServerError,
+        State, Topology, PROTOCOL_VERSION,
+    };
+
+    fn api() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        super::ws_filter(Default::default(), None)
+    }
+
+    async fn recv_peer_event(client: &mut WsClient) -> PeerEvent {
+        let message = client.recv().await;
+        serde_json::from_str(message.unwrap().to_str().unwrap()).unwrap()
+    }
+
+    async fn recv_id(client: &mut WsClient) -> PeerId {
+        match recv_peer_event(client).await {
+            PeerEvent::IdAssigned(id) => id,
+            event => panic!("expected an id, got {:?}", event),
+        }
+    }
 
-    // warning: See comment for ws_filter
-    #[allow(opaque_hidden_inferred_bound)]
-    fn api() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        super::ws_filter(Default::default())
+    /// Joins over a real connection, which unlike a [`WsClient`] can be
+    /// dropped without closing it, as if the connection was lost
+    async fn join_and_lose_connection(
+        api: impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Send + Sync + 'static,
+        path: &str,
+    ) -> PeerId {
+        let (addr, server) = warp::serve(api).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let (mut client, _response) =
+            tokio_tungstenite::connect_async(format!("ws://{addr}{path}"))
+                .await
+                .expect("connect");
+        let message = client.next().await.expect("id").expect("id");
+        match serde_json::from_str(message.to_text().unwrap()).unwrap() {
+            PeerEvent::IdAssigned(id) => id,
+            event => panic!("expected an id, got {:?}", event),
+        }
     }
 
     #[tokio::test]
@@ -321,26 +875,20 @@ mod tests {
             .handshake(api.clone())
             .await
             .expect("handshake");
-
-        client_a
-            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
-            .await;
+        recv_id(&mut client_a).await;
 
         let mut client_b = warp::test::ws()
             .path("/room_a")
             .handshake(api)
             .await
             .expect("handshake");
-
-        client_b
-            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
-            .await;
+        let id_b = recv_id(&mut client_b).await;
 
         let a_msg = client_a.recv().await;
         let new_peer_event: PeerEvent =
             serde_json::from_str(a_msg.unwrap().to_str().unwrap()).unwrap();
 
-        assert_eq!(new_peer_event, PeerEvent::NewPeer("uuid-b".to_string()));
+        assert_eq!(new_peer_event, PeerEvent::NewPeer(id_b));
     }
 
     #[tokio::test]
@@ -353,20 +901,14 @@ mod tests {
             .handshake(api.clone())
             .await
             .expect("handshake");
-
-        client_a
-            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
-            .await;
+        let id_a = recv_id(&mut client_a).await;
 
         let mut client_b = warp::test::ws()
             .path("/room_a")
             .handshake(api)
             .await
             .expect("handshake");
-
-        client_b
-            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
-            .await;
+        recv_id(&mut client_b).await;
 
         let a_msg = client_a.recv().await;
         let new_peer_event: PeerEvent =
@@ -392,121 +934,487 @@ mod tests {
             signal_event,
             PeerEvent::Signal {
                 data: serde_json::Value::String("123".to_string()),
-                sender: "uuid-a".to_string(),
+                sender: id_a,
             }
         );
     }
 
-    async fn recv_peer_event(client: &mut WsClient) -> PeerEvent {
-        let message = client.recv().await;
-        serde_json::from_str(message.unwrap().to_str().unwrap()).unwrap()
-    }
-
     #[tokio::test]
-    async fn match_pairs() {
+    async fn peer_left() {
         let _ = pretty_env_logger::try_init();
         let api = api();
 
         let mut client_a = warp::test::ws()
-            .path("/room_name?next=2")
+            .path("/room_a")
             .handshake(api.clone())
             .await
             .expect("handshake");
-
-        client_a
-            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
-            .await;
+        recv_id(&mut client_a).await;
 
         let mut client_b = warp::test::ws()
-            .path("/room_name?next=2")
-            .handshake(api.clone())
+            .path("/room_a")
+            .handshake(api)
             .await
             .expect("handshake");
+        let id_b = recv_id(&mut client_b).await;
 
-        client_b
-            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
-            .await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_b.clone())
+        );
 
-        let mut client_c = warp::test::ws()
-            .path("/room_name?next=2")
-            .handshake(api.clone())
-            .await
-            .expect("handshake");
+        drop(client_b);
 
-        client_c
-            .send(Message::text(r#"{"Uuid": "uuid-c"}"#.to_string()))
-            .await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::PeerLeft(id_b)
+        );
+    }
 
-        let mut client_d = warp::test::ws()
-            .path("/room_name?next=2")
+    #[tokio::test]
+    async fn idle_peer_evicted() {
+        let _ = pretty_env_logger::try_init();
+        let api = super::ws_filter(
+            Arc::new(Mutex::new(State::new(16, Duration::from_millis(300)))),
+            None,
+        );
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a")
             .handshake(api.clone())
             .await
             .expect("handshake");
+        let id_a = recv_id(&mut client_a).await;
 
-        client_d
-            .send(Message::text(r#"{"Uuid": "uuid-d"}"#.to_string()))
-            .await;
-
-        // Clients should be matched in pairs as they arrive, i.e. a + b and c + d
-        let new_peer_b = recv_peer_event(&mut client_a).await;
-        let new_peer_d = recv_peer_event(&mut client_c).await;
-
-        assert_eq!(new_peer_b, PeerEvent::NewPeer("uuid-b".to_string()));
-        assert_eq!(new_peer_d, PeerEvent::NewPeer("uuid-d".to_string()));
+        let mut client_b = warp::test::ws()
+            .path("/room_a")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        recv_id(&mut client_b).await;
 
-        let timeout = time::sleep(Duration::from_millis(100));
-        pin_mut!(timeout);
-        select! {
-            _ = client_a.recv() => panic!("unexpected message"),
-            _ = client_b.recv() => panic!("unexpected message"),
-            _ = client_c.recv() => panic!("unexpected message"),
-            _ = client_d.recv() => panic!("unexpected message"),
-            _ = &mut timeout => {}
+        // Only b keeps itself alive
+        let keep_alive = serde_json::to_string(&PeerRequest::KeepAlive).unwrap();
+        for _ in 0..5 {
+            time::sleep(Duration::from_millis(100)).await;
+            client_b.send_text(keep_alive.clone()).await;
         }
+
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::PeerLeft(id_a)
+        );
     }
+
     #[tokio::test]
-    async fn match_pair_and_other_alone_room_without_next() {
+    async fn reconnect_keeps_id() {
         let _ = pretty_env_logger::try_init();
         let api = api();
 
         let mut client_a = warp::test::ws()
-            .path("/room_name?next=2")
+            .path("/room_a")
             .handshake(api.clone())
             .await
             .expect("handshake");
+        recv_id(&mut client_a).await;
 
-        client_a
-            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
-            .await;
+        let mut old_client_b = warp::test::ws()
+            .path("/room_a?resume=secret_b")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_b = recv_id(&mut old_client_b).await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_b.clone())
+        );
 
+        // b reconnects before the server noticed its old connection is gone
         let mut client_b = warp::test::ws()
-            .path("/room_name")
+            .path("/room_a?resume=secret_b")
             .handshake(api.clone())
             .await
             .expect("handshake");
+        assert_eq!(recv_id(&mut client_b).await, id_b);
+        old_client_b.recv_closed().await.expect("closed");
 
-        client_b
-            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
-            .await;
-
+        // a wasn't told b left, so the next thing it hears about is c
         let mut client_c = warp::test::ws()
-            .path("/room_name?next=2")
-            .handshake(api.clone())
+            .path("/room_a")
+            .handshake(api)
             .await
             .expect("handshake");
+        let id_c = recv_id(&mut client_c).await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_c.clone())
+        );
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::NewPeer(id_c)
+        );
+    }
 
-        client_c
-            .send(Message::text(r#"{"Uuid": "uuid-c"}"#.to_string()))
-            .await;
-
-        // Clients should be matched in pairs as they arrive, i.e. a + b and c + d
-        let new_peer_c = recv_peer_event(&mut client_a).await;
+    #[tokio::test]
+    async fn reconnect_receives_missed_events() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
 
-        assert_eq!(new_peer_c, PeerEvent::NewPeer("uuid-c".to_string()));
+        let id_a = join_and_lose_connection(api.clone(), "/room_a?resume=secret_a").await;
+        time::sleep(Duration::from_millis(100)).await;
 
-        let timeout = time::sleep(Duration::from_millis(100));
-        pin_mut!(timeout);
-        select! {
+        let mut client_b = warp::test::ws()
+            .path("/room_a")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_b = recv_id(&mut client_b).await;
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?resume=secret_a")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        assert_eq!(recv_id(&mut client_a).await, id_a);
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_b)
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnect_after_grace_period() {
+        let _ = pretty_env_logger::try_init();
+        let state = State::new(16, Duration::from_secs(60))
+            .with_reconnect_grace_period(Duration::from_millis(200));
+        let api = super::ws_filter(Arc::new(Mutex::new(state)), None);
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        recv_id(&mut client_a).await;
+
+        let id_b = join_and_lose_connection(api.clone(), "/room_a?resume=secret_b").await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_b.clone())
+        );
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::PeerLeft(id_b.clone())
+        );
+
+        // Too late, b joins again as a new peer
+        let mut client_b = warp::test::ws()
+            .path("/room_a?resume=secret_b")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        assert_ne!(recv_id(&mut client_b).await, id_b);
+    }
+
+    #[tokio::test]
+    async fn evicted_peer_left_immediately() {
+        let _ = pretty_env_logger::try_init();
+        let api = super::ws_filter(
+            Arc::new(Mutex::new(State::new(16, Duration::from_millis(300)))),
+            None,
+        );
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        recv_id(&mut client_a).await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a?resume=secret_b")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        let id_b = recv_id(&mut client_b).await;
+
+        // Only a keeps itself alive
+        let keep_alive = serde_json::to_string(&PeerRequest::KeepAlive).unwrap();
+        for _ in 0..5 {
+            time::sleep(Duration::from_millis(100)).await;
+            client_a.send_text(keep_alive.clone()).await;
+        }
+
+        // b is gone for good, even though it could have resumed
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_b.clone())
+        );
+        let event = time::timeout(Duration::from_secs(1), recv_peer_event(&mut client_a))
+            .await
+            .expect("peer left before the grace period");
+        assert_eq!(event, PeerEvent::PeerLeft(id_b));
+    }
+
+    #[tokio::test]
+    async fn closed_peer_left_immediately() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        recv_id(&mut client_a).await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a?resume=secret_b")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        let id_b = recv_id(&mut client_b).await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_b.clone())
+        );
+
+        // Leaving on purpose doesn't wait for the grace period
+        client_b.send(Message::close()).await;
+        let event = time::timeout(Duration::from_secs(1), recv_peer_event(&mut client_a))
+            .await
+            .expect("peer left before the grace period");
+        assert_eq!(event, PeerEvent::PeerLeft(id_b));
+    }
+
+    #[tokio::test]
+    async fn peer_left_completed_next_room() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_name?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_a = recv_id(&mut client_a).await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_name?next=2")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        recv_id(&mut client_b).await;
+
+        // The room is complete and forgotten, but b still needs to be told
+        recv_peer_event(&mut client_a).await;
+        drop(client_a);
+
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::PeerLeft(id_a)
+        );
+    }
+
+    #[tokio::test]
+    async fn room_full() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut clients = vec![];
+        for _ in 0..2 {
+            let mut client = warp::test::ws()
+                .path("/room_a?max=2")
+                .handshake(api.clone())
+                .await
+                .expect("handshake");
+            recv_id(&mut client).await;
+            clients.push(client);
+        }
+
+        let mut client_c = warp::test::ws()
+            .path("/room_a?max=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+
+        assert_eq!(
+            recv_peer_event(&mut client_c).await,
+            PeerEvent::Error(ServerError::RoomFull)
+        );
+        client_c.recv_closed().await.expect("closed");
+
+        // The limit belongs to the room, asking for a higher one doesn't
+        // get around it
+        let mut client_d = warp::test::ws()
+            .path("/room_a?max=4")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        assert_eq!(
+            recv_peer_event(&mut client_d).await,
+            PeerEvent::Error(ServerError::RoomFull)
+        );
+        client_d.recv_closed().await.expect("closed");
+    }
+
+    #[tokio::test]
+    async fn version_mismatch() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path(&format!("/room_a?v={}", PROTOCOL_VERSION + 1))
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::Error(ServerError::VersionMismatch {
+                server: PROTOCOL_VERSION
+            })
+        );
+        client_a.recv_closed().await.expect("closed");
+
+        let mut client_b = warp::test::ws()
+            .path(&format!("/room_a?v={PROTOCOL_VERSION}"))
+            .handshake(api)
+            .await
+            .expect("handshake");
+        recv_id(&mut client_b).await;
+    }
+
+    #[tokio::test]
+    async fn server_max_room_size() {
+        let _ = pretty_env_logger::try_init();
+        let api = super::ws_filter(
+            Arc::new(Mutex::new(State::new(1, Duration::from_secs(60)))),
+            None,
+        );
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?max=5")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        recv_id(&mut client_a).await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a?max=5")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::Error(ServerError::RoomFull)
+        );
+    }
+
+    #[tokio::test]
+    async fn room_full_leaves_no_room() {
+        let _ = pretty_env_logger::try_init();
+        let state = Arc::new(Mutex::new(State::new(0, Duration::from_secs(60))));
+        let api = super::ws_filter(state.clone(), None);
+
+        let mut client = warp::test::ws()
+            .path("/room_a?public=true")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        assert_eq!(
+            recv_peer_event(&mut client).await,
+            PeerEvent::Error(ServerError::RoomFull)
+        );
+        client.recv_closed().await.expect("closed");
+
+        let state = state.lock().await;
+        assert!(state.rooms.is_empty());
+        assert!(state.public_rooms().is_empty());
+    }
+
+    #[tokio::test]
+    async fn match_pairs() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_name?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        recv_id(&mut client_a).await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_name?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_b = recv_id(&mut client_b).await;
+
+        let mut client_c = warp::test::ws()
+            .path("/room_name?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        recv_id(&mut client_c).await;
+
+        let mut client_d = warp::test::ws()
+            .path("/room_name?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_d = recv_id(&mut client_d).await;
+
+        // Clients should be matched in pairs as they arrive, i.e. a + b and c + d
+        let new_peer_b = recv_peer_event(&mut client_a).await;
+        let new_peer_d = recv_peer_event(&mut client_c).await;
+
+        assert_eq!(new_peer_b, PeerEvent::NewPeer(id_b));
+        assert_eq!(new_peer_d, PeerEvent::NewPeer(id_d));
+
+        let timeout = time::sleep(Duration::from_millis(100));
+        pin_mut!(timeout);
+        select! {
+            _ = client_a.recv() => panic!("unexpected message"),
+            _ = client_b.recv() => panic!("unexpected message"),
+            _ = client_c.recv() => panic!("unexpected message"),
+            _ = client_d.recv() => panic!("unexpected message"),
+            _ = &mut timeout => {}
+        }
+    }
+    #[tokio::test]
+    async fn match_pair_and_other_alone_room_without_next() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_name?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        recv_id(&mut client_a).await;
+
+        let mut client_b = warp::test::ws()
+            .path("/room_name")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        recv_id(&mut client_b).await;
+
+        let mut client_c = warp::test::ws()
+            .path("/room_name?next=2")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_c = recv_id(&mut client_c).await;
+
+        // Clients should be matched in pairs as they arrive, i.e. a + b and c + d
+        let new_peer_c = recv_peer_event(&mut client_a).await;
+
+        assert_eq!(new_peer_c, PeerEvent::NewPeer(id_c));
+
+        let timeout = time::sleep(Duration::from_millis(100));
+        pin_mut!(timeout);
+        select! {
             _ = client_a.recv() => panic!("unexpected message"),
             _ = client_b.recv() => panic!("unexpected message"),
             _ = client_c.recv() => panic!("unexpected message"),
@@ -524,45 +1432,35 @@ mod tests {
             .handshake(api.clone())
             .await
             .expect("handshake");
+        recv_id(&mut client_a).await;
 
         let mut client_b = warp::test::ws()
             .path("/scope_2?next=2")
             .handshake(api.clone())
             .await
             .expect("handshake");
+        recv_id(&mut client_b).await;
 
         let mut client_c = warp::test::ws()
             .path("/scope_1?next=2")
             .handshake(api.clone())
             .await
             .expect("handshake");
+        let id_c = recv_id(&mut client_c).await;
 
         let mut client_d = warp::test::ws()
             .path("/scope_2?next=2")
             .handshake(api.clone())
             .await
             .expect("handshake");
-
-        client_a
-            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
-            .await;
-        client_c
-            .send(Message::text(r#"{"Uuid": "uuid-c"}"#.to_string()))
-            .await;
-        client_b
-            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
-            .await;
-
-        client_d
-            .send(Message::text(r#"{"Uuid": "uuid-d"}"#.to_string()))
-            .await;
+        let id_d = recv_id(&mut client_d).await;
 
         // Clients should be matched in pairs as they arrive, i.e. a + c and b + d
         let new_peer_c = recv_peer_event(&mut client_a).await;
         let new_peer_d = recv_peer_event(&mut client_b).await;
 
-        assert_eq!(new_peer_c, PeerEvent::NewPeer("uuid-c".to_string()));
-        assert_eq!(new_peer_d, PeerEvent::NewPeer("uuid-d".to_string()));
+        assert_eq!(new_peer_c, PeerEvent::NewPeer(id_c));
+        assert_eq!(new_peer_d, PeerEvent::NewPeer(id_d));
 
         let timeout = time::sleep(Duration::from_millis(100));
         pin_mut!(timeout);
@@ -584,60 +1482,47 @@ mod tests {
             .handshake(api.clone())
             .await
             .expect("handshake");
+        recv_id(&mut client_a).await;
 
         let mut client_b = warp::test::ws()
             .path("/scope_1?next=3")
             .handshake(api.clone())
             .await
             .expect("handshake");
+        recv_id(&mut client_b).await;
 
         let mut client_c = warp::test::ws()
             .path("/scope_1?next=2")
             .handshake(api.clone())
             .await
             .expect("handshake");
+        let id_c = recv_id(&mut client_c).await;
 
         let mut client_d = warp::test::ws()
             .path("/scope_1?next=3")
             .handshake(api.clone())
             .await
             .expect("handshake");
+        let id_d = recv_id(&mut client_d).await;
 
         let mut client_e = warp::test::ws()
             .path("/scope_1?next=3")
             .handshake(api.clone())
             .await
             .expect("handshake");
-
-        client_a
-            .send(Message::text(r#"{"Uuid": "uuid-a"}"#.to_string()))
-            .await;
-        client_c
-            .send(Message::text(r#"{"Uuid": "uuid-c"}"#.to_string()))
-            .await;
-        client_b
-            .send(Message::text(r#"{"Uuid": "uuid-b"}"#.to_string()))
-            .await;
-
-        client_d
-            .send(Message::text(r#"{"Uuid": "uuid-d"}"#.to_string()))
-            .await;
-
-        client_e
-            .send(Message::text(r#"{"Uuid": "uuid-e"}"#.to_string()))
-            .await;
+        let id_e = recv_id(&mut client_e).await;
 
         // Clients should be matched in pairs as they arrive, i.e. a + c and (b + d ; b + e ; d + e)
         let new_peer_c = recv_peer_event(&mut client_a).await;
         let new_peer_d = recv_peer_event(&mut client_b).await;
         let new_peer_e = recv_peer_event(&mut client_b).await;
-        assert_eq!(new_peer_e, PeerEvent::NewPeer("uuid-e".to_string()));
+        assert_eq!(new_peer_e, PeerEvent::NewPeer(id_e.clone()));
         let new_peer_e = recv_peer_event(&mut client_d).await;
 
-        assert_eq!(new_peer_c, PeerEvent::NewPeer("uuid-c".to_string()));
-        assert_eq!(new_peer_d, PeerEvent::NewPeer("uuid-d".to_string()));
-        assert_eq!(new_peer_d, PeerEvent::NewPeer("uuid-d".to_string()));
-        assert_eq!(new_peer_e, PeerEvent::NewPeer("uuid-e".to_string()));
+        assert_eq!(new_peer_c, PeerEvent::NewPeer(id_c));
+        assert_eq!(new_peer_d, PeerEvent::NewPeer(id_d.clone()));
+        assert_eq!(new_peer_d, PeerEvent::NewPeer(id_d.clone()));
+        assert_eq!(new_peer_e, PeerEvent::NewPeer(id_e.clone()));
 
         let timeout = time::sleep(Duration::from_millis(100));
         pin_mut!(timeout);
@@ -651,6 +1536,186 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn star_room() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?topology=star")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_a = recv_id(&mut client_a).await;
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::HostAssigned(id_a.clone())
+        );
+
+        let mut client_b = warp::test::ws()
+            .path("/room_a?topology=star")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_b = recv_id(&mut client_b).await;
+        assert_eq!(
+            recv_peer_event(&mut client_b).await,
+            PeerEvent::HostAssigned(id_a.clone())
+        );
+
+        let mut client_c = warp::test::ws()
+            .path("/room_a?topology=star")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        let id_c = recv_id(&mut client_c).await;
+        assert_eq!(
+            recv_peer_event(&mut client_c).await,
+            PeerEvent::HostAssigned(id_a.clone())
+        );
+
+        // Only the host is told about new peers
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_b.clone())
+        );
+        assert_eq!(
+            recv_peer_event(&mut client_a).await,
+            PeerEvent::NewPeer(id_c.clone())
+        );
+
+        let timeout = time::sleep(Duration::from_millis(100));
+        pin_mut!(timeout);
+        select! {
+            _ = client_a.recv() => panic!("unexpected message"),
+            _ = client_b.recv() => panic!("unexpected message"),
+            _ = client_c.recv() => panic!("unexpected message"),
+            _ = &mut timeout => {}
+        }
+    }
+
+    #[tokio::test]
+    async fn star_host_left() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut client_a = warp::test::ws()
+            .path("/room_a?topology=star")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_a = recv_id(&mut client_a).await;
+
+        let mut clients = vec![];
+        for _ in 0..2 {
+            let mut client = warp::test::ws()
+                .path("/room_a?topology=star")
+                .handshake(api.clone())
+                .await
+                .expect("handshake");
+            let id = recv_id(&mut client).await;
+            assert_eq!(
+                recv_peer_event(&mut client).await,
+                PeerEvent::HostAssigned(id_a.clone())
+            );
+            clients.push((client, id));
+        }
+        drop(client_a);
+
+        // Both clients are told about the new host, which is one of them
+        let mut new_hosts = vec![];
+        for (client, _) in &mut clients {
+            assert_eq!(
+                recv_peer_event(client).await,
+                PeerEvent::PeerLeft(id_a.clone())
+            );
+            match recv_peer_event(client).await {
+                PeerEvent::HostAssigned(host) => new_hosts.push(host),
+                event => panic!("expected a host, got {:?}", event),
+            }
+        }
+        assert_eq!(new_hosts[0], new_hosts[1]);
+        let (mut host, id_host) = clients
+            .iter()
+            .position(|(_, id)| id == &new_hosts[0])
+            .map(|i| clients.swap_remove(i))
+            .expect("host is a client");
+
+        // And the new host connects to the other client
+        assert_eq!(
+            recv_peer_event(&mut host).await,
+            PeerEvent::NewPeer(clients[0].1.clone())
+        );
+
+        // New peers join the new host
+        let mut client_d = warp::test::ws()
+            .path("/room_a?topology=star")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        let id_d = recv_id(&mut client_d).await;
+        assert_eq!(
+            recv_peer_event(&mut client_d).await,
+            PeerEvent::HostAssigned(id_host)
+        );
+        assert_eq!(recv_peer_event(&mut host).await, PeerEvent::NewPeer(id_d));
+    }
+
+    #[tokio::test]
+    async fn star_host_left_completed_next_room() {
+        let _ = pretty_env_logger::try_init();
+        let api = api();
+
+        let mut clients = vec![];
+        for _ in 0..3 {
+            let mut client = warp::test::ws()
+                .path("/room_a?next=3&topology=star")
+                .handshake(api.clone())
+                .await
+                .expect("handshake");
+            let id = recv_id(&mut client).await;
+            recv_peer_event(&mut client).await;
+            clients.push((client, id));
+        }
+        let (client_a, id_a) = clients.remove(0);
+
+        // The next group is filling up with a host of its own
+        let mut client_d = warp::test::ws()
+            .path("/room_a?next=3&topology=star")
+            .handshake(api.clone())
+            .await
+            .expect("handshake");
+        let id_d = recv_id(&mut client_d).await;
+        assert_eq!(
+            recv_peer_event(&mut client_d).await,
+            PeerEvent::HostAssigned(id_d.clone())
+        );
+
+        drop(client_a);
+        for (client, _) in &mut clients {
+            assert_eq!(
+                recv_peer_event(client).await,
+                PeerEvent::PeerLeft(id_a.clone())
+            );
+            assert!(matches!(
+                recv_peer_event(client).await,
+                PeerEvent::HostAssigned(_)
+            ));
+        }
+
+        // The new host of the completed group doesn't host the next one
+        let mut client_e = warp::test::ws()
+            .path("/room_a?next=3&topology=star")
+            .handshake(api)
+            .await
+            .expect("handshake");
+        recv_id(&mut client_e).await;
+        assert_eq!(
+            recv_peer_event(&mut client_e).await,
+            PeerEvent::HostAssigned(id_d)
+        );
+    }
+
     #[test]
     fn requested_room() {
         assert_eq!(
@@ -660,7 +1725,91 @@ mod tests {
     }
     #[test]
     fn requested_scope() {
-        assert_eq!(parse_room_next(QueryParam { next: Some(3) }), Some(3));
-        assert_eq!(parse_room_next(QueryParam { next: None }), None);
+        assert_eq!(
+            parse_room_next(QueryParam {
+                next: Some(3),
+                max: None,
+                public: false,
+                topology: Topology::Mesh,
+                resume: None,
+                v: None,
+            }),
+            Some(3)
+        );
+        assert_eq!(
+            parse_room_next(QueryParam {
+                next: None,
+                max: None,
+                public: false,
+                topology: Topology::Mesh,
+                resume: None,
+                v: None,
+            }),
+            None
+        );
+    }
+    #[test]
+    fn requested_public() {
+        assert!(parse_room_public(QueryParam {
+            next: None,
+            max: None,
+            public: true,
+            topology: Topology::Mesh,
+            resume: None,
+            v: None,
+        }));
+    }
+    #[test]
+    fn requested_topology() {
+        assert_eq!(
+            parse_room_topology(QueryParam {
+                next: None,
+                max: None,
+                public: false,
+                topology: Topology::Star,
+                resume: None,
+                v: None,
+            }),
+            Topology::Star
+        );
+    }
+    #[test]
+    fn requested_version() {
+        assert_eq!(
+            parse_protocol_version(QueryParam {
+                next: None,
+                max: None,
+                public: false,
+                topology: Topology::Mesh,
+                resume: None,
+                v: Some(2),
+            }),
+            Some(2)
+        );
+    }
+    #[test]
+    fn requested_max() {
+        assert_eq!(
+            parse_room_max(QueryParam {
+                next: None,
+                max: Some(4),
+                public: false,
+                topology: Topology::Mesh,
+                resume: None,
+                v: None,
+            }),
+            Some(4)
+        );
+        assert_eq!(
+            parse_room_max(QueryParam {
+                next: None,
+                max: Some(0),
+                public: false,
+                topology: Topology::Mesh,
+                resume: None,
+                v: None,
+            }),
+            Some(1)
+        );
     }
 }
@@ -1,7 +1,7 @@
 use futures::{select, FutureExt};
 use futures_timer::Delay;
-use log::info;
-use matchbox_socket::WebRtcSocket;
+use log::{error, info};
+use matchbox_socket::{PeerState, WebRtcSocket};
 use std::time::Duration;
 
 #[cfg(target_arch = "wasm32")]
@@ -27,22 +27,40 @@ async fn async_main() {
     info!("Connecting to matchbox");
     let (mut socket, loop_fut) = WebRtcSocket::new("ws://localhost:3536/example_room");
 
-    info!("my id is {:?}", socket.id());
-
     let loop_fut = loop_fut.fuse();
     futures::pin_mut!(loop_fut);
 
     let timeout = Delay::new(Duration::from_millis(100));
     futures::pin_mut!(timeout);
 
+    let mut my_id = None;
+
     loop {
-        for peer in socket.accept_new_connections() {
-            info!("Found a peer {:?}", peer);
-            let packet = "hello friend!".as_bytes().to_vec().into_boxed_slice();
-            socket.send(packet, peer);
+        // The signalling server assigns our id once we're connected
+        if my_id.is_none() {
+            my_id = socket.id();
+            if let Some(id) = &my_id {
+                info!("my id is {:?}", id);
+            }
+        }
+
+        for (peer, state) in socket.update_peers() {
+            match state {
+                PeerState::Connected => {
+                    info!("Found a peer {:?}", peer);
+                    let packet = "hello friend!".as_bytes().to_vec().into_boxed_slice();
+                    socket.send(packet, peer);
+                }
+                PeerState::Disconnected => {
+                    info!("Peer {:?} disconnected", peer);
+                }
+                PeerState::ConnectionFailed => {
+                    info!("Couldn't connect to peer {:?}", peer);
+                }
+            }
         }
 
-        for (peer, packet) in socket.receive() {
+        for (peer, packet) in socket.receive().unwrap_or_default() {
             info!("Received from {:?}: {:?}", peer, packet);
         }
 
@@ -51,7 +69,10 @@ async fn async_main() {
                 timeout.reset(Duration::from_millis(100));
             }
 
-            _ = &mut loop_fut => {
+            result = &mut loop_fut => {
+                if let Err(e) = result {
+                    error!("Message loop failed: {}", e);
+                }
                 break;
             }
         }
@@ -1,18 +1,27 @@
 use ggrs::{Message, PlayerType};
+use log::warn;
 
 use crate::WebRtcSocket;
 
 impl WebRtcSocket {
     /// Returns a Vec of connected peers as [`ggrs::PlayerType`]
+    ///
+    /// # Panics
+    ///
+    /// Panics if the signalling server hasn't assigned us an id yet, see
+    /// [`WebRtcSocket::id`].
     #[must_use]
     pub fn players(&self) -> Vec<PlayerType<String>> {
+        let our_id = self
+            .id()
+            .expect("players() called before the socket was assigned an id");
         // needs to be consistent order across all peers
         let mut ids = self.connected_peers();
-        ids.push(self.id().to_owned());
+        ids.push(our_id.clone());
         ids.sort();
         ids.iter()
             .map(|id| {
-                if id == self.id() {
+                if id == &our_id {
                     PlayerType::Local
                 } else {
                     PlayerType::Remote(id.to_owned())
@@ -31,8 +40,15 @@ impl ggrs::NonBlockingSocket<String> for WebRtcSocket {
 
     fn receive_all_messages(&mut self) -> Vec<(String, Message)> {
         // let fake_socket_addrs = self.fake_socket_addrs.clone();
+        let packets = match self.receive() {
+            Ok(packets) => packets,
+            Err(e) => {
+                warn!("failed to receive messages: {}", e);
+                return vec![];
+            }
+        };
         let mut messages = vec![];
-        for (id, packet) in self.receive().into_iter() {
+        for (id, packet) in packets {
             let msg = bincode::deserialize(&packet).unwrap();
             messages.push((id, msg));
         }
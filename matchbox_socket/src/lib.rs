@@ -5,4 +5,11 @@
 mod ggrs_socket;
 mod webrtc_socket;
 
-pub use webrtc_socket::{RtcIceServerConfig, WebRtcSocket, WebRtcSocketConfig};
+pub use webrtc_socket::{
+    CandidateType, ChannelConfig, HandshakePolicy, PeerEvent, PeerRequest, PeerSignal, PeerState,
+    PeerStats, ReconnectPolicy, RtcIceServerConfig, ServerError, Signaller, SignallerFuture,
+    SocketError, Topology, WebRtcSocket, WebRtcSocketConfig,
+};
+
+#[cfg(feature = "link-conditioner")]
+pub use webrtc_socket::LinkConditionerConfig;
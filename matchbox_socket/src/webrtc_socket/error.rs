@@ -0,0 +1,45 @@
+use crate::webrtc_socket::messages::{ServerError, PROTOCOL_VERSION};
+
+/// Errors that can occur while running a [`WebRtcSocket`](crate::WebRtcSocket)
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SocketError {
+    /// Failed to connect to the signalling server, e.g. because of a bad room
+    /// url, or because the server is unreachable
+    #[error("failed to connect to signalling server: {0}")]
+    ConnectionFailed(String),
+    /// The connection to the signalling server broke, or the server sent
+    /// something we didn't understand
+    #[error("signalling protocol error: {0}")]
+    Signalling(String),
+    /// The signalling server turned us away because the room is full
+    #[error("the room is full")]
+    RoomFull,
+    /// The signalling server speaks a different protocol version than we do,
+    /// so one of them needs updating
+    #[error(
+        "the signalling server speaks protocol version {server}, but we speak version {}",
+        PROTOCOL_VERSION
+    )]
+    VersionMismatch {
+        /// The version the server speaks
+        server: u32,
+    },
+    /// The socket's message loop is no longer running, so messages can't be
+    /// sent or received
+    #[error("the message loop is not running")]
+    MessageLoopClosed,
+}
+
+impl From<ServerError> for SocketError {
+    fn from(error: ServerError) -> Self {
+        match error {
+            ServerError::RoomFull => SocketError::RoomFull,
+            ServerError::VersionMismatch { server } => SocketError::VersionMismatch { server },
+        }
+    }
+}
+
+/// Connecting to a peer took too long, even after retrying
+#[derive(Debug, thiserror::Error)]
+#[error("handshake timed out")]
+pub(crate) struct HandshakeTimedOut;
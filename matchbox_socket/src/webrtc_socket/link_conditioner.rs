@@ -0,0 +1,340 @@
+use std::time::Duration;
+
+use futures::{
+    future::{self, Either},
+    stream::FuturesUnordered,
+    Future, StreamExt,
+};
+use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures_timer::Delay;
+use futures_util::select;
+use log::debug;
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use crate::webrtc_socket::{messages::PeerId, Packet, Recipients};
+
+/// Simulated network conditions, see [`WebRtcSocketConfig::link_conditioner`]
+///
+/// The conditions are applied separately to packets sent to and received
+/// from each peer, so the round trip latency is twice
+/// [`LinkConditionerConfig::latency`].
+///
+/// [`WebRtcSocketConfig::link_conditioner`]: crate::WebRtcSocketConfig::link_conditioner
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkConditionerConfig {
+    /// How long each packet is delayed
+    pub latency: Duration,
+    /// Maximum random deviation from [`LinkConditionerConfig::latency`]
+    pub jitter: Duration,
+    /// Probability that a packet is dropped, between 0 and 1
+    pub loss: f64,
+    /// Probability that a packet is delivered twice, between 0 and 1
+    pub duplicate: f64,
+    /// Probability that a packet is held back, between 0 and 1
+    ///
+    /// Held back packets are delayed by an additional `latency + jitter`,
+    /// so they usually arrive after packets sent shortly after them.
+    pub reorder: f64,
+    /// Seed for the random number generators, so runs are reproducible
+    pub seed: u64,
+}
+
+impl Default for LinkConditionerConfig {
+    fn default() -> Self {
+        LinkConditionerConfig {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+/// Decides the fate of each packet
+struct Conditions {
+    config: LinkConditionerConfig,
+    rng: SmallRng,
+}
+
+impl Conditions {
+    fn new(config: LinkConditionerConfig, seed: u64) -> Self {
+        Self {
+            config,
+            rng: SmallRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Returns the delay for each copy of a packet that should be delivered
+    fn delays(&mut self) -> Vec<Duration> {
+        if self.chance(self.config.loss) {
+            return vec![];
+        }
+        let copies = if self.chance(self.config.duplicate) {
+            2
+        } else {
+            1
+        };
+        (0..copies).map(|_| self.delay()).collect()
+    }
+
+    fn delay(&mut self) -> Duration {
+        let latency = self.config.latency.as_secs_f64();
+        let jitter = self.config.jitter.as_secs_f64();
+        let mut delay = latency + self.rng.gen_range(-jitter..=jitter);
+        if self.chance(self.config.reorder) {
+            delay += latency + jitter;
+        }
+        Duration::from_secs_f64(delay.max(0.0))
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        self.rng.gen_bool(probability.clamp(0.0, 1.0))
+    }
+}
+
+async fn delayed<T>(item: T, delay: Duration) -> T {
+    if !delay.is_zero() {
+        Delay::new(delay).await;
+    }
+    item
+}
+
+/// Relays packets between the socket and the message loop, applying the
+/// given network conditions in both directions
+///
+/// Completes once the socket is dropped and all outgoing packets have been
+/// handed to the message loop.
+async fn link_conditioner_loop(
+    config: LinkConditionerConfig,
+    mut from_socket: UnboundedReceiver<(Recipients, usize, Packet)>,
+    to_message_loop: UnboundedSender<(Recipients, usize, Packet)>,
+    from_message_loop: Vec<UnboundedReceiver<(PeerId, Packet)>>,
+    to_socket: Vec<UnboundedSender<(PeerId, Packet)>>,
+) {
+    debug!("Link conditioner started: {config:?}");
+
+    // Separate generators per direction, so one doesn't affect the other
+    let mut outgoing = Conditions::new(config.clone(), config.seed);
+    let mut incoming = Conditions::new(config.clone(), config.seed.wrapping_add(1));
+
+    let mut from_message_loop = futures::stream::select_all(
+        from_message_loop
+            .into_iter()
+            .enumerate()
+            .map(|(channel, rx)| rx.map(move |(peer, packet)| (peer, channel, packet))),
+    );
+
+    let mut outgoing_in_flight = FuturesUnordered::new();
+    let mut incoming_in_flight = FuturesUnordered::new();
+    let mut socket_dropped = false;
+
+    loop {
+        select! {
+            message = from_socket.next() => {
+                match message {
+                    Some((recipients, channel, packet)) => {
+                        for peer in recipients.iter() {
+                            for delay in outgoing.delays() {
+                                outgoing_in_flight.push(delayed((peer.clone(), channel, packet.clone()), delay));
+                            }
+                        }
+                    }
+                    None => socket_dropped = true,
+                }
+            }
+
+            message = from_message_loop.next() => {
+                if let Some((peer, channel, packet)) = message {
+                    for delay in incoming.delays() {
+                        incoming_in_flight.push(delayed((peer.clone(), channel, packet.clone()), delay));
+                    }
+                }
+            }
+
+            message = outgoing_in_flight.select_next_some() => {
+                let (peer, channel, packet) = message;
+                // The message loop may be gone, which the socket finds out on its own
+                let _ = to_message_loop.unbounded_send((Recipients::One(peer), channel, packet));
+            }
+
+            message = incoming_in_flight.select_next_some() => {
+                let (peer, channel, packet) = message;
+                // The socket may have been dropped, in which case nobody cares
+                let _ = to_socket[channel].unbounded_send((peer, packet));
+            }
+
+            complete => break,
+        }
+
+        if socket_dropped && outgoing_in_flight.is_empty() {
+            break;
+        }
+    }
+}
+
+/// Puts a link conditioner between the socket and the message loop, if
+/// configured
+///
+/// Returns the ends of the queues the message loop should use instead, along
+/// with a future that needs to be polled for packets to flow.
+#[allow(clippy::type_complexity)]
+pub(crate) fn interpose(
+    config: Option<LinkConditionerConfig>,
+    peer_messages_out_rx: UnboundedReceiver<(Recipients, usize, Packet)>,
+    messages_from_peers_tx: Vec<UnboundedSender<(PeerId, Packet)>>,
+) -> (
+    UnboundedReceiver<(Recipients, usize, Packet)>,
+    Vec<UnboundedSender<(PeerId, Packet)>>,
+    impl Future<Output = ()>,
+) {
+    let config = match config {
+        Some(config) => config,
+        None => {
+            return (
+                peer_messages_out_rx,
+                messages_from_peers_tx,
+                Either::Left(future::pending()),
+            )
+        }
+    };
+    let (to_message_loop, conditioned_messages_out_rx) = futures_channel::mpsc::unbounded();
+    let (conditioned_messages_from_peers_tx, from_message_loop) = messages_from_peers_tx
+        .iter()
+        .map(|_| futures_channel::mpsc::unbounded())
+        .unzip();
+    (
+        conditioned_messages_out_rx,
+        conditioned_messages_from_peers_tx,
+        Either::Right(link_conditioner_loop(
+            config,
+            peer_messages_out_rx,
+            to_message_loop,
+            from_message_loop,
+            messages_from_peers_tx,
+        )),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Conditions, LinkConditionerConfig};
+
+    const PACKETS: usize = 10_000;
+
+    fn delays(config: LinkConditionerConfig, seed: u64) -> Vec<Vec<Duration>> {
+        let mut conditions = Conditions::new(config, seed);
+        (0..PACKETS).map(|_| conditions.delays()).collect()
+    }
+
+    #[test]
+    fn same_seed_same_fate() {
+        let config = LinkConditionerConfig {
+            latency: Duration::from_millis(50),
+            jitter: Duration::from_millis(10),
+            loss: 0.1,
+            duplicate: 0.1,
+            reorder: 0.1,
+            seed: 0,
+        };
+        assert_eq!(delays(config.clone(), 1), delays(config.clone(), 1));
+        assert_ne!(delays(config.clone(), 1), delays(config, 2));
+    }
+
+    #[test]
+    fn perfect_link() {
+        let delays = delays(LinkConditionerConfig::default(), 0);
+        assert!(delays.iter().all(|copies| copies == &[Duration::ZERO]));
+    }
+
+    #[test]
+    fn loss_and_duplicate_rates() {
+        let delays = delays(
+            LinkConditionerConfig {
+                loss: 0.2,
+                duplicate: 0.5,
+                ..Default::default()
+            },
+            0,
+        );
+        let lost = delays.iter().filter(|copies| copies.is_empty()).count();
+        let duplicated = delays.iter().filter(|copies| copies.len() == 2).count();
+        assert!(delays.iter().all(|copies| copies.len() <= 2));
+
+        let loss = lost as f64 / PACKETS as f64;
+        assert!((0.18..0.22).contains(&loss), "loss rate was {}", loss);
+        // Only packets that aren't lost can be duplicated
+        let duplicate = duplicated as f64 / (PACKETS - lost) as f64;
+        assert!(
+            (0.48..0.52).contains(&duplicate),
+            "duplicate rate was {}",
+            duplicate
+        );
+    }
+
+    #[test]
+    fn delays_within_jitter() {
+        let latency = Duration::from_millis(100);
+        let jitter = Duration::from_millis(20);
+        let delays: Vec<_> = delays(
+            LinkConditionerConfig {
+                latency,
+                jitter,
+                ..Default::default()
+            },
+            0,
+        )
+        .concat();
+        assert_eq!(delays.len(), PACKETS);
+
+        let min = *delays.iter().min().unwrap();
+        let max = *delays.iter().max().unwrap();
+        assert!(min >= latency - jitter, "min delay was {:?}", min);
+        assert!(max <= latency + jitter, "max delay was {:?}", max);
+        // And the whole range is used
+        assert!(min < latency - jitter / 2, "min delay was {:?}", min);
+        assert!(max > latency + jitter / 2, "max delay was {:?}", max);
+    }
+
+    #[test]
+    fn reordered_packets_held_back() {
+        let latency = Duration::from_millis(100);
+        let jitter = Duration::from_millis(20);
+        let delays = delays(
+            LinkConditionerConfig {
+                latency,
+                jitter,
+                reorder: 1.0,
+                ..Default::default()
+            },
+            0,
+        )
+        .concat();
+
+        // Held back by another latency + jitter
+        let held_back = latency + jitter;
+        assert!(delays.iter().all(|delay| (latency - jitter + held_back
+            ..=latency + jitter + held_back)
+            .contains(delay)));
+    }
+
+    #[test]
+    fn jitter_never_negative() {
+        let delays = delays(
+            LinkConditionerConfig {
+                latency: Duration::from_millis(10),
+                jitter: Duration::from_millis(50),
+                ..Default::default()
+            },
+            0,
+        )
+        .concat();
+        assert!(delays.iter().any(|delay| delay.is_zero()));
+        assert!(delays
+            .iter()
+            .all(|delay| *delay <= Duration::from_millis(60)));
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use log::warn;
+
+use crate::webrtc_socket::{
+    messages::PeerId, Packet, PeerMessagesOut, PeerState, Recipients, WebRtcSocket,
+    WebRtcSocketConfig,
+};
+
+/// The receiving ends of a loopback peer, owned by other sockets in the mesh
+#[derive(Debug, Clone)]
+struct LoopbackPeer {
+    messages_tx: Vec<UnboundedSender<(PeerId, Packet)>>,
+    peer_state_tx: UnboundedSender<(PeerId, PeerState)>,
+}
+
+/// Delivers packets directly to the other sockets of a loopback mesh
+#[derive(Debug)]
+pub(crate) struct LoopbackRouter {
+    id: PeerId,
+    peers: HashMap<PeerId, LoopbackPeer>,
+    /// Our own receiving ends, kept so they stay open even if all other
+    /// sockets are dropped, just like when the message loop is running
+    _own: LoopbackPeer,
+}
+
+impl LoopbackRouter {
+    pub fn send(&mut self, recipients: Recipients, channel: usize, packet: Packet) {
+        for peer in recipients.iter() {
+            match self.peers.get(peer) {
+                // The receiving socket may have been dropped, in which case
+                // it has already been reported as disconnected
+                Some(receiver) => {
+                    let _ = receiver.messages_tx[channel]
+                        .unbounded_send((self.id.clone(), packet.clone()));
+                }
+                None => warn!("couldn't find loopback peer {peer}, dropping packet"),
+            }
+        }
+    }
+}
+
+impl Drop for LoopbackRouter {
+    fn drop(&mut self) {
+        for peer in self.peers.values() {
+            let _ = peer
+                .peer_state_tx
+                .unbounded_send((self.id.clone(), PeerState::Disconnected));
+        }
+    }
+}
+
+impl WebRtcSocket {
+    /// Create `n` sockets that are connected to each other in-process,
+    /// without a signalling server or WebRTC
+    ///
+    /// Packets are delivered immediately when sent, so no message loop needs
+    /// to run. All peers are reported as connected from the start, and as
+    /// disconnected when their socket is dropped. Peer ids are `loopback-0`,
+    /// `loopback-1` and so on, in the order of the returned sockets.
+    ///
+    /// Intended for testing code that uses the socket.
+    pub fn new_loopback_mesh(n: usize) -> Vec<WebRtcSocket> {
+        WebRtcSocket::new_loopback_mesh_with_config(n, &WebRtcSocketConfig::default())
+    }
+
+    /// Create `n` sockets that are connected to each other in-process, with
+    /// the channels in the given [`WebRtcSocketConfig`]
+    ///
+    /// Only [`WebRtcSocketConfig::channels`] is used, see
+    /// [`WebRtcSocket::new_loopback_mesh`] for details.
+    ///
+    /// # Panics
+    ///
+    /// Panics if [`WebRtcSocketConfig::channels`] is empty.
+    pub fn new_loopback_mesh_with_config(
+        n: usize,
+        config: &WebRtcSocketConfig,
+    ) -> Vec<WebRtcSocket> {
+        assert!(
+            !config.channels.is_empty(),
+            "at least one data channel must be configured"
+        );
+
+        let ids: Vec<PeerId> = (0..n).map(|i| format!("loopback-{i}")).collect();
+
+        #[allow(clippy::type_complexity)]
+        let (peers, receivers): (
+            Vec<LoopbackPeer>,
+            Vec<(
+                Vec<UnboundedReceiver<(PeerId, Packet)>>,
+                UnboundedReceiver<(PeerId, PeerState)>,
+            )>,
+        ) = ids
+            .iter()
+            .map(|_| {
+                let (messages_tx, messages_rx) = config
+                    .channels
+                    .iter()
+                    .map(|_| futures_channel::mpsc::unbounded())
+                    .unzip();
+                let (peer_state_tx, peer_state_rx) = futures_channel::mpsc::unbounded();
+                (
+                    LoopbackPeer {
+                        messages_tx,
+                        peer_state_tx,
+                    },
+                    (messages_rx, peer_state_rx),
+                )
+            })
+            .unzip();
+
+        ids.iter()
+            .zip(receivers)
+            .enumerate()
+            .map(|(i, (id, (messages_from_peers, peer_state_rx)))| {
+                let others: HashMap<PeerId, LoopbackPeer> = ids
+                    .iter()
+                    .cloned()
+                    .zip(peers.iter().cloned())
+                    .filter(|(other, _)| other != id)
+                    .collect();
+                for other in others.keys() {
+                    peers[i]
+                        .peer_state_tx
+                        .unbounded_send((other.clone(), PeerState::Connected))
+                        .expect("receiver is alive");
+                }
+                WebRtcSocket {
+                    messages_from_peers,
+                    peer_state_rx,
+                    // There are no connection statistics in a loopback mesh
+                    peer_stats_rx: futures_channel::mpsc::unbounded().1,
+                    peer_messages_out: PeerMessagesOut::Loopback(LoopbackRouter {
+                        id: id.clone(),
+                        peers: others,
+                        _own: peers[i].clone(),
+                    }),
+                    peers: vec![],
+                    stats: HashMap::new(),
+                    // Loopback ids are known up front
+                    id_rx: futures_channel::mpsc::unbounded().1,
+                    id: Some(id.clone()),
+                    // A full mesh has no host
+                    host_rx: futures_channel::mpsc::unbounded().1,
+                    host: None,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ChannelConfig, PeerState, WebRtcSocket, WebRtcSocketConfig};
+
+    #[test]
+    fn all_peers_connected() {
+        let mut sockets = WebRtcSocket::new_loopback_mesh(3);
+        let mut peers = sockets[0].accept_new_connections();
+        peers.sort();
+        assert_eq!(peers, vec!["loopback-1", "loopback-2"]);
+        assert_eq!(sockets[1].connected_peers().len(), 0);
+        assert_eq!(sockets[1].accept_new_connections().len(), 2);
+    }
+
+    #[test]
+    fn send_and_receive() {
+        let mut sockets = WebRtcSocket::new_loopback_mesh(2);
+        sockets[0].send(Box::new([1, 2, 3]), "loopback-1");
+        assert_eq!(sockets[0].receive().unwrap(), vec![]);
+        assert_eq!(
+            sockets[1].receive().unwrap(),
+            vec![("loopback-0".to_string(), vec![1u8, 2, 3].into_boxed_slice())]
+        );
+    }
+
+    #[test]
+    fn broadcast_on_channel() {
+        let mut sockets = WebRtcSocket::new_loopback_mesh_with_config(
+            3,
+            &WebRtcSocketConfig {
+                channels: vec![ChannelConfig::unreliable(), ChannelConfig::reliable()],
+                ..Default::default()
+            },
+        );
+        sockets[2].accept_new_connections();
+        sockets[2].broadcast_on_channel(Box::new([42]), 1);
+        for socket in &mut sockets[..2] {
+            assert_eq!(socket.receive().unwrap(), vec![]);
+            assert_eq!(
+                socket.receive_on_channel(1).unwrap(),
+                vec![("loopback-2".to_string(), vec![42u8].into_boxed_slice())]
+            );
+        }
+    }
+
+    #[test]
+    fn disconnect_on_drop() {
+        let mut sockets = WebRtcSocket::new_loopback_mesh(2);
+        sockets[0].accept_new_connections();
+        sockets.pop();
+        assert_eq!(
+            sockets[0].update_peers(),
+            vec![("loopback-1".to_string(), PeerState::Disconnected)]
+        );
+        assert!(sockets[0].connected_peers().is_empty());
+        assert_eq!(sockets[0].receive().unwrap(), vec![]);
+    }
+}
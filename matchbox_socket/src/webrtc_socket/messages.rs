@@ -1,26 +1,9 @@
-use serde::{Deserialize, Serialize};
-
-pub(crate) type PeerId = String;
+pub use matchbox_protocol::{
+    PeerId, PeerSignal, ServerError, Topology, KEEP_ALIVE_INTERVAL, PROTOCOL_VERSION,
+};
 
 /// Events go from signalling server to peer
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
-pub enum PeerEvent {
-    NewPeer(PeerId),
-    Signal { sender: PeerId, data: PeerSignal },
-}
+pub type PeerEvent = matchbox_protocol::PeerEvent<PeerSignal>;
 
-// TODO: move back into lib
 /// Requests go from peer to signalling server
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
-pub enum PeerRequest {
-    Uuid(PeerId),
-    Signal { receiver: PeerId, data: PeerSignal },
-    KeepAlive,
-}
-
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
-pub enum PeerSignal {
-    IceCandidate(String),
-    Offer(String),
-    Answer(String),
-}
+pub type PeerRequest = matchbox_protocol::PeerRequest<PeerSignal>;
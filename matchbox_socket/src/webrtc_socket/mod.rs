@@ -1,30 +1,52 @@
-use std::pin::Pin;
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
 
-use futures::{Future, FutureExt, StreamExt};
+use futures::{Future, FutureExt, Sink, Stream, StreamExt};
 use futures_util::select;
-use log::debug;
+use log::{debug, warn};
 
+mod error;
+#[cfg(feature = "link-conditioner")]
+mod link_conditioner;
+#[cfg(any(test, feature = "loopback"))]
+mod loopback;
 mod messages;
 mod signal_peer;
+mod signaller;
+mod stats;
 
-const KEEP_ALIVE_INTERVAL: u64 = 10_000;
+const STATS_INTERVAL: u64 = 2_000;
 const DATA_CHANNEL_ID: u16 = 124;
 
+/// Whether we give way when a peer sends us an offer while ours is pending
+///
+/// This is the "perfect negotiation" pattern, exactly one side of each pair
+/// is polite: it rolls back its own offer and answers the other one, while
+/// the impolite side ignores the colliding offer. See
+/// <https://developer.mozilla.org/en-US/docs/Web/API/WebRTC_API/Perfect_negotiation>
+fn is_polite(own_id: &PeerId, peer_id: &PeerId) -> bool {
+    own_id < peer_id
+}
+
 // TODO: maybe use cfg-if to make this slightly tidier
 #[cfg(not(target_arch = "wasm32"))]
 mod native {
     mod message_loop;
-    mod signalling_loop;
+    mod websocket_signaller;
     pub use message_loop::*;
-    pub use signalling_loop::*;
+    pub use websocket_signaller::*;
 }
 
 #[cfg(target_arch = "wasm32")]
 mod wasm {
     mod message_loop;
-    mod signalling_loop;
+    mod websocket_signaller;
     pub use message_loop::*;
-    pub use signalling_loop::*;
+    pub use websocket_signaller::*;
 }
 
 #[cfg(not(target_arch = "wasm32"))]
@@ -32,11 +54,55 @@ use native::*;
 #[cfg(target_arch = "wasm32")]
 use wasm::*;
 
-use messages::*;
-use uuid::Uuid;
+pub use error::SocketError;
+#[cfg(feature = "link-conditioner")]
+pub use link_conditioner::LinkConditionerConfig;
+use messages::PeerId;
+pub use messages::{PeerEvent, PeerRequest, PeerSignal, ServerError, Topology};
+use signaller::{reconnecting_signalling_loop, with_topology};
+pub use signaller::{Signaller, SignallerFuture};
+pub use stats::{CandidateType, PeerStats};
 
 type Packet = Box<[u8]>;
 
+/// The peers an outgoing packet is addressed to
+#[derive(Debug)]
+enum Recipients {
+    One(PeerId),
+    Many(Vec<PeerId>),
+}
+
+impl Recipients {
+    fn iter(&self) -> impl Iterator<Item = &PeerId> {
+        match self {
+            Recipients::One(peer) => std::slice::from_ref(peer).iter(),
+            Recipients::Many(peers) => peers.iter(),
+        }
+    }
+}
+
+/// The state of a connection to a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerState {
+    /// The peer is connected
+    ///
+    /// This means all of the peer's data channels are open and messages can
+    /// be sent to it.
+    Connected,
+    /// The peer has disconnected
+    ///
+    /// This happens when a data channel to the peer closes, or its peer
+    /// connection fails and restarting ICE doesn't bring it back within the
+    /// handshake timeout. Messages sent to the peer after this point are lost.
+    Disconnected,
+    /// Connecting to the peer failed
+    ///
+    /// The handshake with the peer timed out too many times, see
+    /// [`HandshakePolicy`], or failed otherwise, e.g. because the peer sent
+    /// an offer we couldn't use. The peer was never connected.
+    ConnectionFailed,
+}
+
 /// General configuration options for a WebRtc connection
 ///
 /// See [`WebRtcSocket::new_with_config`]
@@ -53,14 +119,173 @@ pub struct WebRtcSocketConfig {
     /// or: `wss://matchbox.example.com/your_game?next=2`
     ///
     /// The last form will pair player in the order they connect.
+    ///
+    /// Other rooms can limit how many peers they let in with `?max=4`, up to
+    /// the limit configured on the server. Joining a full room fails with
+    /// [`SocketError::RoomFull`].
+    ///
+    /// How the peers connect to each other is set with
+    /// [`WebRtcSocketConfig::topology`] rather than in the url.
     pub room_url: String,
-    /// Configuration for the (single) ICE server
-    pub ice_server: RtcIceServerConfig,
+    /// How the peers in the room connect to each other
+    ///
+    /// Peers connect to every other peer with [`Topology::Mesh`], the
+    /// default. With [`Topology::Star`], they only connect to the host
+    /// instead, see [`WebRtcSocket::host_id`]. Like the room's `max`, it's
+    /// set by the peer that creates the room.
+    ///
+    /// Not used with [`WebRtcSocket::new_with_signaller`].
+    pub topology: Topology,
+    /// Token to authenticate with the matchbox server, if it requires one
+    ///
+    /// Sent as a bearer token in the `Authorization` header on native, and
+    /// as the `token` query parameter in the browser, where websockets can't
+    /// have custom headers.
+    pub auth_token: Option<String>,
+    /// Configuration for the ICE servers used to establish peer connections
+    ///
+    /// Typically a STUN server, optionally followed by one or more TURN
+    /// servers for peers that can't connect directly.
+    pub ice_servers: Vec<RtcIceServerConfig>,
+    /// Configuration for the data channels opened to each peer
+    ///
+    /// Channels are addressed by their index in this list, see
+    /// [`WebRtcSocket::send_on_channel`] and
+    /// [`WebRtcSocket::receive_on_channel`]. The list must not be empty.
+    pub channels: Vec<ChannelConfig>,
+    /// How to reconnect to the signalling server if the connection is lost
+    ///
+    /// New peers can't join while the connection is down. If we reconnect
+    /// within the signalling server's grace period, we keep our id and the
+    /// other peers never know we were gone. Otherwise the server tells them
+    /// we left, and they disconnect from us, and connect again when we
+    /// rejoin under a new id.
+    pub reconnect: ReconnectPolicy,
+    /// How long to wait for the handshake with each peer, and how often to
+    /// retry it
+    pub handshake: HandshakePolicy,
+    /// Simulated network conditions for testing, `None` by default
+    #[cfg(feature = "link-conditioner")]
+    pub link_conditioner: Option<LinkConditionerConfig>,
+}
+
+/// Configuration for reconnecting to the signalling server
+///
+/// The delay between attempts starts at [`ReconnectPolicy::initial_delay`]
+/// and doubles after each failed attempt, up to
+/// [`ReconnectPolicy::max_delay`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// How many times to try to reconnect before giving up
+    ///
+    /// The count is reset once a connection has been established. `0`
+    /// disables reconnecting.
+    pub max_attempts: u32,
+    /// Delay before the first reconnection attempt
+    pub initial_delay: Duration,
+    /// Upper bound for the delay between reconnection attempts
+    pub max_delay: Duration,
+}
+
+impl ReconnectPolicy {
+    /// Never try to reconnect, the socket fails as soon as the connection to
+    /// the signalling server is lost
+    pub fn disabled() -> Self {
+        ReconnectPolicy {
+            max_attempts: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the delay before the given reconnection attempt, starting at 0
+    fn delay(&self, attempt: u32) -> Duration {
+        self.initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Configuration for the WebRTC handshakes with peers
+///
+/// A handshake that doesn't complete within [`HandshakePolicy::timeout`] is
+/// retried with a fresh peer connection. Once the retries run out, the peer
+/// is reported as [`PeerState::ConnectionFailed`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakePolicy {
+    /// How long a single attempt may take, from the offer until all data
+    /// channels are open
+    ///
+    /// Also how long an ICE restart may take once connected, before the peer
+    /// is disconnected.
+    pub timeout: Duration,
+    /// How many times to retry a handshake that timed out
+    ///
+    /// Both peers should use the same value, since the accepting peer waits
+    /// for every attempt of the offering one. `0` disables retrying.
+    pub max_retries: u32,
+}
+
+impl Default for HandshakePolicy {
+    fn default() -> Self {
+        HandshakePolicy {
+            timeout: Duration::from_secs(10),
+            max_retries: 2,
+        }
+    }
+}
+
+/// Configuration options for a data channel
+/// See also: <https://developer.mozilla.org/en-US/docs/Web/API/RTCPeerConnection/createDataChannel#options>
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelConfig {
+    /// Whether messages sent on the channel are guaranteed to arrive in order
+    pub ordered: bool,
+    /// Maximum number of retransmit attempts of a message before giving up
+    ///
+    /// `None` means messages are retransmitted until they arrive, unless
+    /// [`ChannelConfig::max_packet_lifetime`] is set.
+    pub max_retransmits: Option<u16>,
+    /// Maximum time in milliseconds to keep retransmitting a message
+    ///
+    /// Can not be combined with [`ChannelConfig::max_retransmits`].
+    pub max_packet_lifetime: Option<u16>,
+}
+
+impl ChannelConfig {
+    /// Messages sent via an unreliable channel may arrive in any order or not at all, but arrive as
+    /// quickly as possible
+    pub fn unreliable() -> Self {
+        ChannelConfig {
+            ordered: false,
+            max_retransmits: Some(0),
+            max_packet_lifetime: None,
+        }
+    }
+
+    /// Messages sent via a reliable channel are guaranteed to arrive in order and will be resent
+    /// until they arrive
+    pub fn reliable() -> Self {
+        ChannelConfig {
+            ordered: true,
+            max_retransmits: None,
+            max_packet_lifetime: None,
+        }
+    }
 }
 
 /// Configuration options for an ICE server connection.
 /// See also: <https://developer.mozilla.org/en-US/docs/Web/API/RTCIceServer#example>
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RtcIceServerConfig {
     /// An ICE server instance can have several URLs
     pub urls: Vec<String>,
@@ -92,7 +317,14 @@ impl Default for WebRtcSocketConfig {
     fn default() -> Self {
         WebRtcSocketConfig {
             room_url: "ws://localhost:3536/example_room".to_string(),
-            ice_server: RtcIceServerConfig::default(),
+            topology: Topology::default(),
+            auth_token: None,
+            ice_servers: vec![RtcIceServerConfig::default()],
+            channels: vec![ChannelConfig::unreliable()],
+            reconnect: ReconnectPolicy::default(),
+            handshake: HandshakePolicy::default(),
+            #[cfg(feature = "link-conditioner")]
+            link_conditioner: None,
         }
     }
 }
@@ -102,18 +334,63 @@ impl Default for WebRtcSocketConfig {
 /// Used to send and receive messages from other peers
 #[derive(Debug)]
 pub struct WebRtcSocket {
-    messages_from_peers: futures_channel::mpsc::UnboundedReceiver<(PeerId, Packet)>,
-    new_connected_peers: futures_channel::mpsc::UnboundedReceiver<PeerId>,
-    peer_messages_out: futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>,
+    messages_from_peers: Vec<futures_channel::mpsc::UnboundedReceiver<(PeerId, Packet)>>,
+    peer_state_rx: futures_channel::mpsc::UnboundedReceiver<(PeerId, PeerState)>,
+    peer_stats_rx: futures_channel::mpsc::UnboundedReceiver<(PeerId, PeerStats)>,
+    peer_messages_out: PeerMessagesOut,
     peers: Vec<PeerId>,
-    id: PeerId,
+    stats: HashMap<PeerId, PeerStats>,
+    id_rx: futures_channel::mpsc::UnboundedReceiver<PeerId>,
+    id: Option<PeerId>,
+    host_rx: futures_channel::mpsc::UnboundedReceiver<PeerId>,
+    host: Option<PeerId>,
+}
+
+/// Where packets sent through a [`WebRtcSocket`] go
+#[derive(Debug)]
+enum PeerMessagesOut {
+    /// Handed to the message loop, which sends them over the data channels
+    MessageLoop(futures_channel::mpsc::UnboundedSender<(Recipients, usize, Packet)>),
+    /// Delivered straight to other sockets in the same process
+    #[cfg(any(test, feature = "loopback"))]
+    Loopback(loopback::LoopbackRouter),
+}
+
+impl PeerMessagesOut {
+    fn send(
+        &mut self,
+        recipients: Recipients,
+        channel: usize,
+        packet: Packet,
+    ) -> Result<(), SocketError> {
+        match self {
+            PeerMessagesOut::MessageLoop(sender) => sender
+                .unbounded_send((recipients, channel, packet))
+                .map_err(|_| SocketError::MessageLoopClosed),
+            #[cfg(any(test, feature = "loopback"))]
+            PeerMessagesOut::Loopback(router) => {
+                router.send(recipients, channel, packet);
+                Ok(())
+            }
+        }
+    }
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), SocketError>> {
+        match self {
+            PeerMessagesOut::MessageLoop(sender) => sender
+                .poll_ready(cx)
+                .map_err(|_| SocketError::MessageLoopClosed),
+            #[cfg(any(test, feature = "loopback"))]
+            PeerMessagesOut::Loopback(_) => Poll::Ready(Ok(())),
+        }
+    }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-pub(crate) type MessageLoopFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+pub(crate) type MessageLoopFuture = Pin<Box<dyn Future<Output = Result<(), SocketError>> + Send>>;
 // TODO: figure out if it's possible to implement Send in wasm as well
 #[cfg(target_arch = "wasm32")]
-pub(crate) type MessageLoopFuture = Pin<Box<dyn Future<Output = ()>>>;
+pub(crate) type MessageLoopFuture = Pin<Box<dyn Future<Output = Result<(), SocketError>>>>;
 
 impl WebRtcSocket {
     /// Create a new connection to the given room
@@ -121,6 +398,8 @@ impl WebRtcSocket {
     /// See [`WebRtcSocketConfig::room_url`] for details on the room url.
     ///
     /// The returned future should be awaited in order for messages to be sent and received.
+    /// It resolves when the socket is dropped, or with an error if the socket
+    /// can't continue, see [`SocketError`].
     #[must_use]
     pub fn new<T: Into<String>>(room_url: T) -> (Self, MessageLoopFuture) {
         WebRtcSocket::new_with_config(WebRtcSocketConfig {
@@ -132,57 +411,186 @@ impl WebRtcSocket {
     /// Create a new connection with the given [`WebRtcSocketConfig`]
     ///
     /// The returned future should be awaited in order for messages to be sent and received.
+    ///
+    /// # Panics
+    ///
+    /// Panics if [`WebRtcSocketConfig::channels`] is empty.
     #[must_use]
     pub fn new_with_config(config: WebRtcSocketConfig) -> (Self, MessageLoopFuture) {
-        let (messages_from_peers_tx, messages_from_peers) = futures_channel::mpsc::unbounded();
-        let (new_connected_peers_tx, new_connected_peers) = futures_channel::mpsc::unbounded();
-        let (peer_messages_out_tx, peer_messages_out_rx) =
-            futures_channel::mpsc::unbounded::<(PeerId, Packet)>();
+        let signaller = WebSocketSignaller::new(
+            with_topology(&config.room_url, config.topology),
+            config.auth_token.clone(),
+        );
+        WebRtcSocket::new_with_signaller(config, signaller)
+    }
 
-        // Would perhaps be smarter to let signalling server decide this...
-        let id = Uuid::new_v4().to_string();
+    /// Create a new connection that signals through the given [`Signaller`]
+    /// instead of connecting to a matchbox server
+    ///
+    /// [`WebRtcSocketConfig::room_url`] and [`WebRtcSocketConfig::topology`]
+    /// are not used in this case.
+    ///
+    /// The returned future should be awaited in order for messages to be sent and received.
+    ///
+    /// # Panics
+    ///
+    /// Panics if [`WebRtcSocketConfig::channels`] is empty.
+    #[must_use]
+    pub fn new_with_signaller<S: Signaller>(
+        config: WebRtcSocketConfig,
+        signaller: S,
+    ) -> (Self, MessageLoopFuture) {
+        assert!(
+            !config.channels.is_empty(),
+            "at least one data channel must be configured"
+        );
+        let (messages_from_peers_tx, messages_from_peers) = config
+            .channels
+            .iter()
+            .map(|_| futures_channel::mpsc::unbounded())
+            .unzip();
+        let (peer_state_tx, peer_state_rx) = futures_channel::mpsc::unbounded();
+        let (peer_stats_tx, peer_stats_rx) = futures_channel::mpsc::unbounded();
+        let (id_tx, id_rx) = futures_channel::mpsc::unbounded();
+        let (host_tx, host_rx) = futures_channel::mpsc::unbounded();
+        let (peer_messages_out_tx, peer_messages_out_rx) =
+            futures_channel::mpsc::unbounded::<(Recipients, usize, Packet)>();
 
         (
             Self {
-                id: id.clone(),
+                id_rx,
+                id: None,
+                host_rx,
+                host: None,
                 messages_from_peers,
-                peer_messages_out: peer_messages_out_tx,
-                new_connected_peers,
+                peer_messages_out: PeerMessagesOut::MessageLoop(peer_messages_out_tx),
+                peer_state_rx,
+                peer_stats_rx,
                 peers: vec![],
+                stats: HashMap::new(),
             },
             Box::pin(run_socket(
                 config,
-                id,
+                signaller,
+                id_tx,
+                host_tx,
                 peer_messages_out_rx,
-                new_connected_peers_tx,
+                peer_state_tx,
+                peer_stats_tx,
                 messages_from_peers_tx,
             )),
         )
     }
 
     /// Returns a future that resolves when the given number of peers have connected
-    pub async fn wait_for_peers(&mut self, peers: usize) -> Vec<PeerId> {
+    ///
+    /// Peers that disconnect while waiting, or fail to connect, are not counted.
+    pub async fn wait_for_peers(&mut self, peers: usize) -> Result<Vec<PeerId>, SocketError> {
         debug!("waiting for peers to join");
         let mut addrs = vec![];
-        while let Some(id) = self.new_connected_peers.next().await {
-            addrs.push(id.clone());
+        while let Some((id, state)) = self.peer_state_rx.next().await {
+            self.update_ids();
+            if !self.handle_peer_state_change(&id, state) {
+                continue;
+            }
+            match state {
+                PeerState::Connected => addrs.push(id),
+                PeerState::Disconnected => addrs.retain(|peer| peer != &id),
+                PeerState::ConnectionFailed => {}
+            }
             if addrs.len() == peers {
                 debug!("all peers joined");
-                self.peers.extend(addrs.clone());
-                return addrs;
+                return Ok(addrs);
+            }
+        }
+        Err(SocketError::MessageLoopClosed)
+    }
+
+    /// Returns a future that resolves with the next peer that connects or
+    /// disconnects
+    ///
+    /// [`WebRtcSocket::connected_peers`] is updated accordingly.
+    pub async fn next_peer_change(&mut self) -> Result<(PeerId, PeerState), SocketError> {
+        while let Some((id, state)) = self.peer_state_rx.next().await {
+            self.update_ids();
+            if self.handle_peer_state_change(&id, state) {
+                return Ok((id, state));
+            }
+        }
+        Err(SocketError::MessageLoopClosed)
+    }
+
+    /// Check if any peers have connected or disconnected since the last call
+    ///
+    /// Returns the ids of the peers whose state changed, along with their new
+    /// state. [`WebRtcSocket::connected_peers`] is updated accordingly.
+    pub fn update_peers(&mut self) -> Vec<(PeerId, PeerState)> {
+        self.update_ids();
+        let mut changes = Vec::new();
+        while let Ok(Some((id, state))) = self.peer_state_rx.try_next() {
+            if self.handle_peer_state_change(&id, state) {
+                changes.push((id, state));
             }
         }
-        panic!("Signal server died")
+        changes
     }
 
     /// Check if new peers have connected and if so add them as peers
+    ///
+    /// Disconnected peers are removed from [`WebRtcSocket::connected_peers`],
+    /// but not reported. Use [`WebRtcSocket::update_peers`] to be notified
+    /// about disconnections as well.
     pub fn accept_new_connections(&mut self) -> Vec<PeerId> {
-        let mut ids = Vec::new();
-        while let Ok(Some(id)) = self.new_connected_peers.try_next() {
-            self.peers.push(id.clone());
-            ids.push(id);
+        self.update_peers()
+            .into_iter()
+            .filter_map(|(id, state)| (state == PeerState::Connected).then_some(id))
+            .collect()
+    }
+
+    /// Picks up the ids the signalling server assigned since the last call
+    fn update_ids(&mut self) {
+        while let Ok(Some(id)) = self.id_rx.try_next() {
+            self.id = Some(id);
+        }
+        while let Ok(Some(host)) = self.host_rx.try_next() {
+            self.host = Some(host);
+        }
+    }
+
+    /// Updates the list of connected peers, returns false if nothing changed
+    fn handle_peer_state_change(&mut self, id: &PeerId, state: PeerState) -> bool {
+        match state {
+            PeerState::Connected => {
+                if self.peers.contains(id) {
+                    return false;
+                }
+                self.peers.push(id.clone());
+                true
+            }
+            PeerState::Disconnected => {
+                self.stats.remove(id);
+                let len = self.peers.len();
+                self.peers.retain(|peer| peer != id);
+                self.peers.len() != len
+            }
+            // Never connected, so there's nothing to update, but it's still
+            // news to the application
+            PeerState::ConnectionFailed => true,
+        }
+    }
+
+    /// Returns the latest statistics about the connection to the given peer
+    ///
+    /// Statistics are refreshed by the message loop every few seconds, so
+    /// this returns `None` for peers that have just connected, as well as for
+    /// peers that aren't connected.
+    pub fn peer_stats(&mut self, peer: &PeerId) -> Option<PeerStats> {
+        while let Ok(Some((id, stats))) = self.peer_stats_rx.try_next() {
+            if self.peers.contains(&id) {
+                self.stats.insert(id, stats);
+            }
         }
-        ids
+        self.stats.get(peer).cloned()
     }
 
     /// Returns a Vec of the ids of the connected peers
@@ -190,73 +598,304 @@ impl WebRtcSocket {
         self.peers.clone() // TODO: could probably be an iterator or reference instead?
     }
 
-    /// Call this where you want to handle new received messages
+    /// Call this where you want to handle new received messages from the
+    /// first channel
     ///
     /// messages are removed from the socket when called
-    pub fn receive(&mut self) -> Vec<(PeerId, Packet)> {
-        std::iter::repeat_with(|| self.messages_from_peers.try_next())
-            // .map_while(|poll| match p { // map_while is nightly-only :(
-            .take_while(|p| !p.is_err())
-            .map(|p| match p.unwrap() {
-                Some((id, packet)) => (id, packet),
-                None => todo!("Handle connection closed??"),
-            })
-            .collect()
+    ///
+    /// See also: [`WebRtcSocket::receive_on_channel`]
+    pub fn receive(&mut self) -> Result<Vec<(PeerId, Packet)>, SocketError> {
+        self.receive_on_channel(0)
     }
 
-    /// Send a packet to the given peer
+    /// Call this where you want to handle new received messages from a
+    /// specific channel, as configured in [`WebRtcSocketConfig::channels`]
+    ///
+    /// messages are removed from the socket when called
+    ///
+    /// Returns [`SocketError::MessageLoopClosed`] once the message loop has
+    /// stopped and all remaining messages have been received.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no channel with the given index.
+    pub fn receive_on_channel(
+        &mut self,
+        channel: usize,
+    ) -> Result<Vec<(PeerId, Packet)>, SocketError> {
+        let messages_from_peers = self
+            .messages_from_peers
+            .get_mut(channel)
+            .unwrap_or_else(|| panic!("no channel with index {}", channel));
+        let mut messages = vec![];
+        loop {
+            match messages_from_peers.try_next() {
+                Ok(Some(message)) => messages.push(message),
+                // No more messages right now
+                Err(_) => return Ok(messages),
+                // All senders are gone, report it when there is nothing left to receive
+                Ok(None) if messages.is_empty() => return Err(SocketError::MessageLoopClosed),
+                Ok(None) => return Ok(messages),
+            }
+        }
+    }
+
+    /// Send a packet to the given peer on the first channel
+    ///
+    /// If the packet can't be queued, the error is logged and the packet is
+    /// dropped. Use [`WebRtcSocket::try_send`] to handle errors instead.
+    ///
+    /// See also: [`WebRtcSocket::send_on_channel`]
     pub fn send<T: Into<PeerId>>(&mut self, packet: Packet, id: T) {
-        self.peer_messages_out
-            .unbounded_send((id.into(), packet))
-            .expect("send_to failed");
+        self.send_on_channel(packet, id, 0);
     }
 
-    /// Returns the id of this peer
-    pub fn id(&self) -> &PeerId {
-        &self.id
+    /// Send a packet to the given peer on a specific channel, as configured
+    /// in [`WebRtcSocketConfig::channels`]
+    ///
+    /// If the packet can't be queued, the error is logged and the packet is
+    /// dropped. Use [`WebRtcSocket::try_send_on_channel`] to handle errors
+    /// instead.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no channel with the given index.
+    pub fn send_on_channel<T: Into<PeerId>>(&mut self, packet: Packet, id: T, channel: usize) {
+        if let Err(e) = self.try_send_on_channel(packet, id, channel) {
+            warn!("failed to send packet: {}", e);
+        }
+    }
+
+    /// Send a packet to the given peer on the first channel
+    ///
+    /// Fails with [`SocketError::MessageLoopClosed`] if the message loop is
+    /// no longer running.
+    ///
+    /// See also: [`WebRtcSocket::try_send_on_channel`]
+    pub fn try_send<T: Into<PeerId>>(&mut self, packet: Packet, id: T) -> Result<(), SocketError> {
+        self.try_send_on_channel(packet, id, 0)
+    }
+
+    /// Send a packet to the given peer on a specific channel, as configured
+    /// in [`WebRtcSocketConfig::channels`]
+    ///
+    /// Fails with [`SocketError::MessageLoopClosed`] if the message loop is
+    /// no longer running.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no channel with the given index.
+    pub fn try_send_on_channel<T: Into<PeerId>>(
+        &mut self,
+        packet: Packet,
+        id: T,
+        channel: usize,
+    ) -> Result<(), SocketError> {
+        self.try_send_to(Recipients::One(id.into()), packet, channel)
+    }
+
+    /// Send a packet to all connected peers on the first channel
+    ///
+    /// The packet is only queued once, and shared between the peers.
+    ///
+    /// See also: [`WebRtcSocket::broadcast_on_channel`]
+    pub fn broadcast(&mut self, packet: Packet) {
+        self.broadcast_on_channel(packet, 0);
+    }
+
+    /// Send a packet to all connected peers on a specific channel, as
+    /// configured in [`WebRtcSocketConfig::channels`]
+    ///
+    /// "All connected peers" are the ones returned by
+    /// [`WebRtcSocket::connected_peers`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no channel with the given index.
+    pub fn broadcast_on_channel(&mut self, packet: Packet, channel: usize) {
+        let peers = self.peers.clone();
+        self.send_to_many_on_channel(packet, &peers, channel);
+    }
+
+    /// Send a packet to the given peers on the first channel
+    ///
+    /// The packet is only queued once, and shared between the peers.
+    ///
+    /// See also: [`WebRtcSocket::send_to_many_on_channel`]
+    pub fn send_to_many(&mut self, packet: Packet, peers: &[PeerId]) {
+        self.send_to_many_on_channel(packet, peers, 0);
+    }
+
+    /// Send a packet to the given peers on a specific channel, as configured
+    /// in [`WebRtcSocketConfig::channels`]
+    ///
+    /// If the packet can't be queued, the error is logged and the packet is
+    /// dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no channel with the given index.
+    pub fn send_to_many_on_channel(&mut self, packet: Packet, peers: &[PeerId], channel: usize) {
+        if peers.is_empty() {
+            return;
+        }
+        if let Err(e) = self.try_send_to(Recipients::Many(peers.to_vec()), packet, channel) {
+            warn!("failed to send packet: {}", e);
+        }
+    }
+
+    fn try_send_to(
+        &mut self,
+        recipients: Recipients,
+        packet: Packet,
+        channel: usize,
+    ) -> Result<(), SocketError> {
+        assert!(
+            channel < self.messages_from_peers.len(),
+            "no channel with index {}",
+            channel
+        );
+        self.peer_messages_out.send(recipients, channel, packet)
+    }
+
+    /// Returns the id of this peer, or `None` if the signalling server
+    /// hasn't assigned one yet
+    ///
+    /// The id is assigned shortly after connecting, and picked up along with
+    /// peer changes, by [`WebRtcSocket::update_peers`] and the like. If the
+    /// connection to the signalling server is lost, we keep the id if we
+    /// reconnect within the server's grace period, and get a new one
+    /// otherwise.
+    pub fn id(&self) -> Option<PeerId> {
+        self.id.clone()
+    }
+
+    /// Returns the id of the host of this star room, or `None` if the room
+    /// is a full mesh or the signalling server hasn't told us yet
+    ///
+    /// In rooms joined with [`Topology::Star`], the first peer to join is the
+    /// host, and the only peer the others connect to. If the host leaves, the
+    /// signalling server makes one of the remaining peers the host, which
+    /// then connects to the others. Like [`WebRtcSocket::id`], it's picked
+    /// up along with peer changes.
+    pub fn host_id(&self) -> Option<PeerId> {
+        self.host.clone()
+    }
+
+    /// Whether we're the host of this star room, see
+    /// [`WebRtcSocket::host_id`]
+    pub fn is_host(&self) -> bool {
+        self.id.is_some() && self.id == self.host
+    }
+}
+
+/// Yields messages received on the first channel as they arrive
+///
+/// The stream ends when the message loop stops.
+impl Stream for WebRtcSocket {
+    type Item = (PeerId, Packet);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.messages_from_peers[0].poll_next_unpin(cx)
     }
 }
 
-async fn run_socket(
+/// Sends messages to a single peer on the first channel
+impl Sink<(PeerId, Packet)> for WebRtcSocket {
+    type Error = SocketError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.peer_messages_out.poll_ready(cx)
+    }
+
+    fn start_send(
+        mut self: Pin<&mut Self>,
+        (peer, packet): (PeerId, Packet),
+    ) -> Result<(), Self::Error> {
+        self.try_send(packet, peer)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Packets are handed straight to the message loop
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Closing the queue would stop the message loop, which also ends the
+        // receiving side, so leave it to dropping the socket instead.
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_socket<S: Signaller>(
     config: WebRtcSocketConfig,
-    id: PeerId,
-    peer_messages_out_rx: futures_channel::mpsc::UnboundedReceiver<(PeerId, Packet)>,
-    new_connected_peers_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
-    messages_from_peers_tx: futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>,
-) {
+    signaller: S,
+    id_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
+    host_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
+    peer_messages_out_rx: futures_channel::mpsc::UnboundedReceiver<(Recipients, usize, Packet)>,
+    peer_state_tx: futures_channel::mpsc::UnboundedSender<(PeerId, PeerState)>,
+    peer_stats_tx: futures_channel::mpsc::UnboundedSender<(PeerId, PeerStats)>,
+    messages_from_peers_tx: Vec<futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>>,
+) -> Result<(), SocketError> {
     debug!("Starting WebRtcSocket message loop");
 
+    #[cfg(feature = "link-conditioner")]
+    let (peer_messages_out_rx, messages_from_peers_tx, link_conditioner_fut) =
+        link_conditioner::interpose(
+            config.link_conditioner.clone(),
+            peer_messages_out_rx,
+            messages_from_peers_tx,
+        );
+    // Packets go straight between the socket and the message loop
+    #[cfg(not(feature = "link-conditioner"))]
+    let link_conditioner_fut = futures::future::pending::<()>();
+
     let (requests_sender, requests_receiver) = futures_channel::mpsc::unbounded::<PeerRequest>();
     let (events_sender, events_receiver) = futures_channel::mpsc::unbounded::<PeerEvent>();
 
-    let signalling_loop_fut =
-        signalling_loop(config.room_url.clone(), requests_receiver, events_sender);
+    let signalling_loop_fut = reconnecting_signalling_loop(
+        signaller,
+        config.reconnect.clone(),
+        requests_receiver,
+        events_sender,
+    );
 
     let message_loop_fut = message_loop(
-        id,
+        id_tx,
+        host_tx,
         config,
         requests_sender,
         events_receiver,
         peer_messages_out_rx,
-        new_connected_peers_tx,
+        peer_state_tx,
+        peer_stats_tx,
         messages_from_peers_tx,
     );
 
     let mut message_loop_done = Box::pin(message_loop_fut.fuse());
     let mut signalling_loop_done = Box::pin(signalling_loop_fut.fuse());
+    let mut link_conditioner_done = Box::pin(link_conditioner_fut.fuse());
     loop {
         select! {
-            _ = message_loop_done => {
+            res = message_loop_done => {
+                // The signalling loop follows, once it has left the server
                 debug!("Message loop completed");
-                break;
+                res?;
             }
 
-            _ = signalling_loop_done => {
+            res = signalling_loop_done => {
                 debug!("Signalling loop completed");
-                // todo!{"reconnect?"}
+                res?;
+            }
+
+            _ = link_conditioner_done => {
+                // The socket was dropped, the message loop will follow
+                debug!("Link conditioner completed");
             }
 
             complete => break
         }
     }
+    Ok(())
 }
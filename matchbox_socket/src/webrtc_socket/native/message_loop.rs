@@ -1,50 +1,66 @@
 use async_compat::CompatExt;
 use bytes::Bytes;
 use futures::{
-    future::FusedFuture, pin_mut, stream::FuturesUnordered, Future, FutureExt, SinkExt, StreamExt,
+    future::{Fuse, FusedFuture},
+    pin_mut,
+    stream::FuturesUnordered,
+    Future, FutureExt, StreamExt,
 };
 use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
 use futures_timer::Delay;
 use futures_util::{lock::Mutex, select};
 use log::{debug, warn};
 use std::time::Duration;
-use std::{collections::HashMap, pin::Pin, sync::Arc};
+use std::{collections::HashMap, sync::Arc};
 use webrtc::{
     api::APIBuilder,
     data_channel::{data_channel_init::RTCDataChannelInit, RTCDataChannel},
+    ice::candidate::CandidateType as IceCandidateType,
     ice_transport::{
         ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
+        ice_connection_state::RTCIceConnectionState,
         ice_server::RTCIceServer,
     },
     peer_connection::{
         configuration::RTCConfiguration,
+        offer_answer_options::RTCOfferOptions,
         sdp::{sdp_type::RTCSdpType, session_description::RTCSessionDescription},
+        signaling_state::RTCSignalingState,
         RTCPeerConnection,
     },
+    stats::StatsReportType,
 };
 
 use crate::webrtc_socket::{
-    messages::{PeerEvent, PeerId, PeerRequest, PeerSignal},
+    error::HandshakeTimedOut,
+    is_polite,
+    messages::{PeerEvent, PeerId, PeerRequest, PeerSignal, KEEP_ALIVE_INTERVAL},
     signal_peer::SignalPeer,
-    Packet, WebRtcSocketConfig, DATA_CHANNEL_ID, KEEP_ALIVE_INTERVAL,
+    CandidateType, ChannelConfig, Packet, PeerState, PeerStats, Recipients, SocketError,
+    WebRtcSocketConfig, DATA_CHANNEL_ID, STATS_INTERVAL,
 };
 
+#[allow(clippy::too_many_arguments)]
 pub async fn message_loop(
-    id: PeerId,
+    id_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
+    host_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
     config: WebRtcSocketConfig,
     requests_sender: futures_channel::mpsc::UnboundedSender<PeerRequest>,
     events_receiver: futures_channel::mpsc::UnboundedReceiver<PeerEvent>,
-    peer_messages_out_rx: futures_channel::mpsc::UnboundedReceiver<(PeerId, Packet)>,
-    new_connected_peers_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
-    messages_from_peers_tx: futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>,
-) {
+    peer_messages_out_rx: futures_channel::mpsc::UnboundedReceiver<(Recipients, usize, Packet)>,
+    peer_state_tx: futures_channel::mpsc::UnboundedSender<(PeerId, PeerState)>,
+    peer_stats_tx: futures_channel::mpsc::UnboundedSender<(PeerId, PeerStats)>,
+    messages_from_peers_tx: Vec<futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>>,
+) -> Result<(), SocketError> {
     message_loop_impl(
-        id,
+        id_tx,
+        host_tx,
         &config,
         requests_sender,
         events_receiver,
         peer_messages_out_rx,
-        new_connected_peers_tx,
+        peer_state_tx,
+        peer_stats_tx,
         messages_from_peers_tx,
     )
     // web-rtc is tokio-based so we use compat here to make it work with other async run-times
@@ -52,62 +68,103 @@ pub async fn message_loop(
     .await
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn message_loop_impl(
-    id: PeerId,
+    id_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
+    host_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
     config: &WebRtcSocketConfig,
     requests_sender: futures_channel::mpsc::UnboundedSender<PeerRequest>,
     mut events_receiver: futures_channel::mpsc::UnboundedReceiver<PeerEvent>,
-    mut peer_messages_out_rx: futures_channel::mpsc::UnboundedReceiver<(PeerId, Packet)>,
-    new_connected_peers_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
-    messages_from_peers_tx: futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>,
-) {
+    mut peer_messages_out_rx: futures_channel::mpsc::UnboundedReceiver<(Recipients, usize, Packet)>,
+    peer_state_tx: futures_channel::mpsc::UnboundedSender<(PeerId, PeerState)>,
+    peer_stats_tx: futures_channel::mpsc::UnboundedSender<(PeerId, PeerStats)>,
+    messages_from_peers_tx: Vec<futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>>,
+) -> Result<(), SocketError> {
     debug!("Entering native WebRtcSocket message loop");
 
-    debug!("I am {:?}", id);
-
-    requests_sender
-        .unbounded_send(PeerRequest::Uuid(id))
-        .expect("failed to send uuid");
-
     let mut peer_loops_a = FuturesUnordered::new();
     let mut peer_loops_b = FuturesUnordered::new();
     let mut handshake_signals = HashMap::new();
     let mut connected_peers = HashMap::new();
+    let mut own_id = None;
+    let (peer_state_changes_tx, mut peer_state_changes_rx) = futures_channel::mpsc::unbounded();
 
-    let timeout = Delay::new(Duration::from_millis(KEEP_ALIVE_INTERVAL));
+    let timeout = Delay::new(KEEP_ALIVE_INTERVAL);
     futures::pin_mut!(timeout);
 
     loop {
-        let next_signal_event = events_receiver.next().fuse();
+        // Not fused, so it's skipped once the signalling loop is gone
+        let next_signal_event = events_receiver.next();
         let next_peer_message_out = peer_messages_out_rx.next().fuse();
 
         pin_mut!(next_signal_event, next_peer_message_out);
 
         select! {
             _ = (&mut timeout).fuse() => {
-                requests_sender.unbounded_send(PeerRequest::KeepAlive).expect("send failed");
-                timeout.reset(Duration::from_millis(KEEP_ALIVE_INTERVAL));
+                // Nothing to keep alive if the signalling loop is gone
+                let _ = requests_sender.unbounded_send(PeerRequest::KeepAlive);
+                timeout.reset(KEEP_ALIVE_INTERVAL);
             }
 
-            _ = peer_loops_a.select_next_some() => {
-                debug!("peer finished");
-            },
-            _ = peer_loops_b.select_next_some() => {
-                debug!("peer finished");
+            _ = peer_loops_a.select_next_some() => debug!("peer finished"),
+            _ = peer_loops_b.select_next_some() => debug!("peer finished"),
+
+            (peer, state) = peer_state_changes_rx.select_next_some() => {
+                // Both the data channel and the peer connection may report a
+                // disconnect, only forward the first one.
+                let known_peer = match state {
+                    PeerState::Connected => connected_peers.contains_key(&peer),
+                    PeerState::Disconnected | PeerState::ConnectionFailed => {
+                        handshake_signals.remove(&peer);
+                        connected_peers.remove(&peer).is_some()
+                    }
+                };
+                if known_peer {
+                    debug!("peer {peer} changed state to {state:?}");
+                    // The socket may have been dropped, which we'll notice below
+                    let _ = peer_state_tx.unbounded_send((peer, state));
+                }
             },
 
             message = next_signal_event => {
                 if let Some(event) = message {
                     debug!("{:?}", event);
                     match event {
+                        PeerEvent::IdAssigned(id) => {
+                            debug!("I am {id}");
+                            own_id = Some(id.clone());
+                            // The socket may have been dropped, which we'll notice below
+                            let _ = id_tx.unbounded_send(id);
+                        }
+                        PeerEvent::HostAssigned(host) => {
+                            debug!("{host} is the host");
+                            // The socket may have been dropped, which we'll notice below
+                            let _ = host_tx.unbounded_send(host);
+                        }
+                        PeerEvent::NewPeer(peer_uuid) if connected_peers.contains_key(&peer_uuid) => {
+                            // We're already connected to this peer, keep the existing connection
+                            debug!("ignoring new peer event for known peer {peer_uuid}");
+                        }
                         PeerEvent::NewPeer(peer_uuid) => {
                             let (signal_sender, signal_receiver) = futures_channel::mpsc::unbounded();
                             handshake_signals.insert(peer_uuid.clone(), signal_sender);
                             let signal_peer = SignalPeer::new(peer_uuid.clone(), requests_sender.clone());
-                            let handshake_fut = handshake_offer(signal_peer, signal_receiver, new_connected_peers_tx.clone(), messages_from_peers_tx.clone(), config);
+                            let polite = matches!(&own_id, Some(id) if is_polite(id, &peer_uuid));
+                            let handshake_fut = handshake_offer(signal_peer.clone(), signal_receiver, polite, peer_state_changes_tx.clone(), messages_from_peers_tx.clone(), config);
                             let (to_peer_data_tx, to_peer_data_rx) = futures_channel::mpsc::unbounded();
                             connected_peers.insert(peer_uuid, to_peer_data_tx);
-                            peer_loops_a.push(peer_loop(handshake_fut, to_peer_data_rx));
+                            peer_loops_a.push(peer_loop(signal_peer, polite, handshake_fut, to_peer_data_rx, peer_state_changes_tx.clone(), peer_stats_tx.clone(), config));
+                        }
+                        // Handled by the signalling loop
+                        PeerEvent::Error(_) => {}
+                        PeerEvent::PeerLeft(peer_uuid) => {
+                            // Dropping the peer's queues aborts the handshake, or stops the peer loop
+                            handshake_signals.remove(&peer_uuid);
+                            if connected_peers.remove(&peer_uuid).is_some() {
+                                debug!("peer {peer_uuid} left");
+                                // The socket may have been dropped, which we'll notice below
+                                let _ = peer_state_tx.unbounded_send((peer_uuid, PeerState::Disconnected));
+                            }
                         }
                         PeerEvent::Signal { sender, data } => {
                             let from_peer_sender = handshake_signals.entry(sender.clone()).or_insert_with(|| {
@@ -115,28 +172,42 @@ async fn message_loop_impl(
                                 let signal_peer = SignalPeer::new(sender.clone(), requests_sender.clone());
                                 let (to_peer_data_tx, to_peer_data_rx) = futures_channel::mpsc::unbounded();
                                 // We didn't start signalling with this peer, assume we're the accepting part
-                                let handshake_fut = handshake_accept(signal_peer, from_peer_receiver, new_connected_peers_tx.clone(), messages_from_peers_tx.clone(), config);
-                                connected_peers.insert(sender, to_peer_data_tx);
-                                let peer_loop_fut = peer_loop(handshake_fut, to_peer_data_rx);
+                                let handshake_fut = handshake_accept(signal_peer.clone(), from_peer_receiver, None, peer_state_changes_tx.clone(), messages_from_peers_tx.clone(), config);
+                                connected_peers.insert(sender.clone(), to_peer_data_tx);
+                                let polite = matches!(&own_id, Some(id) if is_polite(id, &sender));
+                                let peer_loop_fut = peer_loop(signal_peer, polite, handshake_fut, to_peer_data_rx, peer_state_changes_tx.clone(), peer_stats_tx.clone(), config);
                                 peer_loops_b.push(peer_loop_fut);
                                 from_peer_sender
                             });
-                            from_peer_sender.unbounded_send(data)
-                                .expect("failed to forward signal to handshaker");
+                            if let Err(e) = from_peer_sender.unbounded_send(data) {
+                                warn!("failed to forward signal to handshaker: {e:?}");
+                            }
                         }
                     }
                 } else {
-                    // Disconnected from signalling server
+                    // Connections to peers we already have keep working, we
+                    // just won't hear about new ones
+                    warn!("Disconnected from signalling server");
                 };
             }
 
             // TODO: maybe use some forward trait instead?
             message = next_peer_message_out => {
                 match message {
-                    Some(message) => {
-                        let peer = &message.0;
-                        let sender = &connected_peers.get(peer).unwrap_or_else(|| panic!("couldn't find data channel for peer {}", peer));
-                        sender.unbounded_send(message.1).unwrap();
+                    Some((recipients, channel, packet)) => {
+                        // Converting to Bytes doesn't copy, and clones only bump a ref count
+                        let packet = Bytes::from(packet);
+                        for peer in recipients.iter() {
+                            match connected_peers.get(peer) {
+                                Some(sender) => {
+                                    if let Err(e) = sender.unbounded_send((channel, packet.clone())) {
+                                        warn!("failed to forward packet to peer {peer}: {e:?}");
+                                    }
+                                }
+                                // The peer most likely disconnected
+                                None => warn!("couldn't find data channel for peer {peer}, dropping packet"),
+                            }
+                        }
                     },
                     None => {
                         // Receiver end of outgoing message channel closed,
@@ -152,7 +223,9 @@ async fn message_loop_impl(
             complete => break
         }
     }
+    Ok(())
 }
+
 struct CandidateTrickle {
     signal_peer: SignalPeer,
     pending: Mutex<Vec<String>>,
@@ -171,7 +244,13 @@ impl CandidateTrickle {
         peer_connection: &RTCPeerConnection,
         candidate: RTCIceCandidate,
     ) {
-        let candidate = candidate.to_json().unwrap().candidate;
+        let candidate = match candidate.to_json() {
+            Ok(candidate) => candidate.candidate,
+            Err(e) => {
+                warn!("failed to serialize local ice candidate: {e:?}");
+                return;
+            }
+        };
 
         // Local candidates can only be sent after the remote description
         if peer_connection.remote_description().await.is_some() {
@@ -191,199 +270,374 @@ impl CandidateTrickle {
             self.signal_peer.send(PeerSignal::IceCandidate(candidate));
         }
     }
+}
+
+/// The connection to a peer, once the handshake is done
+struct Handshake {
+    connection: Arc<RTCPeerConnection>,
+    data_channels: Vec<Arc<RTCDataChannel>>,
+    /// Signals the peer keeps sending, i.e. ICE candidates and ICE restarts
+    signal_receiver: UnboundedReceiver<PeerSignal>,
+    ice_state_rx: UnboundedReceiver<RTCIceConnectionState>,
+}
 
-    async fn listen_for_remote_candidates(
-        peer_connection: Arc<RTCPeerConnection>,
-        mut signal_receiver: UnboundedReceiver<PeerSignal>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        while let Some(signal) = signal_receiver.next().await {
-            match signal {
-                PeerSignal::IceCandidate(candidate) => {
-                    debug!("got an IceCandidate signal! {}", candidate);
-                    peer_connection
-                        .add_ice_candidate(RTCIceCandidateInit {
-                            candidate,
-                            ..Default::default()
-                        })
-                        .await?;
+type HandshakeResult = Result<Handshake, Box<dyn std::error::Error>>;
+
+/// Sends an offer to the peer, or answers the peer's own offer instead if
+/// both of us offered at once and we're the polite side
+///
+/// Attempts that time out are retried with a fresh connection and offer.
+async fn handshake_offer(
+    signal_peer: SignalPeer,
+    mut signal_receiver: UnboundedReceiver<PeerSignal>,
+    polite: bool,
+    peer_state_tx: UnboundedSender<(PeerId, PeerState)>,
+    from_peer_message_tx: Vec<UnboundedSender<(PeerId, Packet)>>,
+    config: &WebRtcSocketConfig,
+) -> HandshakeResult {
+    for attempt in 0..=config.handshake.max_retries {
+        if attempt > 0 {
+            debug!("handshake with {} timed out, retrying", signal_peer.id);
+        }
+        debug!("making offer");
+        let mut pending = PendingConnection::new(
+            &signal_peer,
+            &peer_state_tx,
+            from_peer_message_tx.clone(),
+            config,
+        )
+        .await?;
+
+        let offer = pending.connection.create_offer(None).await?;
+        let sdp = offer.sdp.clone();
+        pending.connection.set_local_description(offer).await?;
+        signal_peer.send(PeerSignal::Offer(sdp));
+
+        let mut timeout = Delay::new(config.handshake.timeout).fuse();
+        loop {
+            match pending.next_event(&mut signal_receiver, &mut timeout).await {
+                HandshakeEvent::Answer(answer) => {
+                    let mut remote_description = RTCSessionDescription::default();
+                    remote_description.sdp = answer;
+                    remote_description.sdp_type = RTCSdpType::Answer;
+                    if let Err(e) = pending
+                        .connection
+                        .set_remote_description(remote_description)
+                        .await
+                    {
+                        // Most likely a late answer to an earlier attempt
+                        warn!("failed to set answer as remote description: {e:?}");
+                        continue;
+                    }
+                    // Can only send candidates once both descriptions are set
+                    pending.trickle.send_pending_candidates().await;
+                }
+                HandshakeEvent::Offer(offer) if polite => {
+                    debug!("offer collision with {}, answering theirs", signal_peer.id);
+                    // webrtc-rs can't roll back a local offer, so start over
+                    // with a fresh connection instead, which amounts to the
+                    // same thing
+                    pending.discard().await;
+                    return handshake_accept(
+                        signal_peer,
+                        signal_receiver,
+                        Some(offer),
+                        peer_state_tx,
+                        from_peer_message_tx,
+                        config,
+                    )
+                    .await;
                 }
-                PeerSignal::Offer(_) => {
-                    warn!("Got an unexpected Offer, while waiting for IceCandidate. Ignoring.")
+                HandshakeEvent::Offer(_) => {
+                    // The polite peer answers our offer instead
+                    debug!("offer collision with {}, ignoring theirs", signal_peer.id);
                 }
-                PeerSignal::Answer(_) => {
-                    warn!("Got an unexpected Answer, while waiting for IceCandidate. Ignoring.")
+                HandshakeEvent::ChannelsReady => {
+                    return Ok(pending.connected(signal_peer, signal_receiver, &peer_state_tx));
                 }
+                HandshakeEvent::TimedOut | HandshakeEvent::IceFailed => {
+                    pending.discard().await;
+                    break;
+                }
+                HandshakeEvent::PeerLeft => return Err(abort_handshake(&pending.connection).await),
             }
         }
-
-        Ok(())
     }
+    Err(connection_failed(&signal_peer, &peer_state_tx))
 }
 
-async fn handshake_offer(
+/// Answers the peer's offer, or waits for it first if `offer` is `None`
+///
+/// If the peer times out and offers again, the answer is made from a fresh
+/// connection.
+async fn handshake_accept(
     signal_peer: SignalPeer,
     mut signal_receiver: UnboundedReceiver<PeerSignal>,
-    new_peer_tx: UnboundedSender<PeerId>,
-    from_peer_message_tx: UnboundedSender<(PeerId, Packet)>,
+    offer: Option<String>,
+    peer_state_tx: UnboundedSender<(PeerId, PeerState)>,
+    from_peer_message_tx: Vec<UnboundedSender<(PeerId, Packet)>>,
     config: &WebRtcSocketConfig,
-) -> Result<
-    (
-        PeerId,
-        Arc<RTCDataChannel>,
-        Pin<Box<dyn FusedFuture<Output = Result<(), Box<dyn std::error::Error>>> + Send>>,
-    ),
-    Box<dyn std::error::Error>,
-> {
-    debug!("making offer");
-    let (connection, trickle) = create_rtc_peer_connection(signal_peer.clone(), config).await?;
-
-    let (channel_ready_tx, mut channel_ready_rx) = futures_channel::mpsc::channel(1);
-    let data_channel = create_data_channel(
-        &connection,
-        channel_ready_tx,
-        signal_peer.id.clone(),
-        new_peer_tx,
-        from_peer_message_tx,
+) -> HandshakeResult {
+    debug!("handshake_accept");
+    // Leave time for all the attempts of the offering side
+    let mut timeout =
+        Delay::new(config.handshake.timeout * (config.handshake.max_retries + 1)).fuse();
+    let mut pending = PendingConnection::new(
+        &signal_peer,
+        &peer_state_tx,
+        from_peer_message_tx.clone(),
+        config,
     )
-    .await;
+    .await?;
 
-    // TODO: maybe pass in options? ice restart etc.?
-    let offer = connection.create_offer(None).await?;
-    let sdp = offer.sdp.clone();
-    connection.set_local_description(offer).await?;
-    signal_peer.send(PeerSignal::Offer(sdp));
-
-    let sdp: String;
+    let mut accepted = false;
+    if let Some(offer) = offer {
+        pending.accept_offer(&signal_peer, offer).await?;
+        accepted = true;
+    }
 
     loop {
-        let signal = signal_receiver
-            .next()
-            .await
-            .ok_or("Signal server connection lost in the middle of a handshake")?;
-
-        match signal {
-            PeerSignal::Answer(answer) => {
-                sdp = answer;
-                break;
+        match pending.next_event(&mut signal_receiver, &mut timeout).await {
+            HandshakeEvent::Offer(offer) => {
+                if accepted {
+                    debug!("{} made a new offer, starting over", signal_peer.id);
+                    pending.discard().await;
+                    pending = PendingConnection::new(
+                        &signal_peer,
+                        &peer_state_tx,
+                        from_peer_message_tx.clone(),
+                        config,
+                    )
+                    .await?;
+                }
+                debug!("received offer");
+                pending.accept_offer(&signal_peer, offer).await?;
+                accepted = true;
             }
-            PeerSignal::Offer(_) => {
-                warn!("Got an unexpected Offer, while waiting for Answer. Ignoring.")
+            HandshakeEvent::Answer(_) => {
+                // We only accept when the peer offered first, so there's no
+                // offer of ours to answer
+                warn!("Got an unexpected Answer, while accepting an Offer. Ignoring.")
             }
-            PeerSignal::IceCandidate(_) => {
-                warn!("Got an unexpected IceCandidate, while waiting for Answer. Ignoring.")
+            HandshakeEvent::ChannelsReady => {
+                return Ok(pending.connected(signal_peer, signal_receiver, &peer_state_tx));
             }
-        };
+            HandshakeEvent::TimedOut => {
+                pending.discard().await;
+                return Err(connection_failed(&signal_peer, &peer_state_tx));
+            }
+            HandshakeEvent::IceFailed => {
+                // The offering side notices too, and makes a new offer
+                debug!(
+                    "connection to {} failed, waiting for a new offer",
+                    signal_peer.id
+                );
+            }
+            HandshakeEvent::PeerLeft => return Err(abort_handshake(&pending.connection).await),
+        }
     }
+}
 
-    let mut remote_description = RTCSessionDescription::default();
-    remote_description.sdp = sdp;
-    remote_description.sdp_type = RTCSdpType::Answer; // TODO: Or leave unspecified?
-    connection
-        .set_remote_description(remote_description)
+/// What interrupted waiting for a handshake to complete
+enum HandshakeEvent {
+    /// The peer sent an offer
+    Offer(String),
+    /// The peer answered our offer
+    Answer(String),
+    /// All data channels are open
+    ChannelsReady,
+    /// The handshake took too long
+    TimedOut,
+    /// No way was found to reach the peer
+    IceFailed,
+    /// Signalling with the peer ended, i.e. the peer left
+    PeerLeft,
+}
+
+/// A peer connection and its data channels, while handshaking
+struct PendingConnection {
+    connection: Arc<RTCPeerConnection>,
+    trickle: Arc<CandidateTrickle>,
+    data_channels: Vec<Arc<RTCDataChannel>>,
+    channel_ready_rx: futures_channel::mpsc::Receiver<u8>,
+    channels_ready: usize,
+    ice_state_rx: UnboundedReceiver<RTCIceConnectionState>,
+}
+
+impl PendingConnection {
+    async fn new(
+        signal_peer: &SignalPeer,
+        peer_state_tx: &UnboundedSender<(PeerId, PeerState)>,
+        from_peer_message_tx: Vec<UnboundedSender<(PeerId, Packet)>>,
+        config: &WebRtcSocketConfig,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let (connection, trickle, ice_state_rx) =
+            create_rtc_peer_connection(signal_peer.clone(), config).await?;
+
+        let (channel_ready_tx, channel_ready_rx) =
+            futures_channel::mpsc::channel(config.channels.len());
+        let data_channels = create_data_channels(
+            &connection,
+            channel_ready_tx,
+            signal_peer.id.clone(),
+            peer_state_tx.clone(),
+            from_peer_message_tx,
+            &config.channels,
+        )
         .await?;
 
-    trickle.send_pending_candidates().await;
-    let mut trickle_fut = Box::pin(
-        CandidateTrickle::listen_for_remote_candidates(connection, signal_receiver).fuse(),
-    );
+        Ok(Self {
+            connection,
+            trickle,
+            data_channels,
+            channel_ready_rx,
+            channels_ready: 0,
+            ice_state_rx,
+        })
+    }
 
-    let mut channel_ready_fut = channel_ready_rx.next();
-    loop {
-        select! {
-            _ = channel_ready_fut => break,
-            // TODO: this means that the signalling is down, should return an
-            // error
-            _ = trickle_fut => continue,
-        };
+    /// Adds remote ICE candidates until something else happens
+    async fn next_event(
+        &mut self,
+        signal_receiver: &mut UnboundedReceiver<PeerSignal>,
+        mut timeout: &mut Fuse<Delay>,
+    ) -> HandshakeEvent {
+        while self.channels_ready < self.data_channels.len() {
+            select! {
+                _ = self.channel_ready_rx.next() => self.channels_ready += 1,
+                signal = signal_receiver.next() => match signal {
+                    Some(PeerSignal::IceCandidate(candidate)) => {
+                        add_ice_candidate(&self.connection, candidate).await;
+                    }
+                    Some(PeerSignal::Offer(offer)) => return HandshakeEvent::Offer(offer),
+                    Some(PeerSignal::Answer(answer)) => return HandshakeEvent::Answer(answer),
+                    None => return HandshakeEvent::PeerLeft,
+                },
+                state = self.ice_state_rx.select_next_some() => {
+                    if state == RTCIceConnectionState::Failed {
+                        return HandshakeEvent::IceFailed;
+                    }
+                }
+                _ = timeout => return HandshakeEvent::TimedOut,
+            }
+        }
+        HandshakeEvent::ChannelsReady
     }
 
-    Ok((signal_peer.id, data_channel, trickle_fut))
-}
+    /// Sets the peer's offer as the remote description, and sends it our answer
+    async fn accept_offer(
+        &self,
+        signal_peer: &SignalPeer,
+        offer: String,
+    ) -> Result<(), webrtc::Error> {
+        answer_offer(&self.connection, signal_peer, offer).await?;
+        // Can only send candidates after sending the local description.
+        self.trickle.send_pending_candidates().await;
+        Ok(())
+    }
 
-async fn handshake_accept(
-    signal_peer: SignalPeer,
-    mut signal_receiver: UnboundedReceiver<PeerSignal>,
-    new_peer_tx: UnboundedSender<PeerId>,
-    from_peer_message_tx: UnboundedSender<(PeerId, Packet)>,
-    config: &WebRtcSocketConfig,
-) -> Result<
-    (
-        PeerId,
-        Arc<RTCDataChannel>,
-        Pin<Box<dyn FusedFuture<Output = Result<(), Box<dyn std::error::Error>>> + Send>>,
-    ),
-    Box<dyn std::error::Error>,
-> {
-    debug!("handshake_accept");
-    let (connection, trickle) = create_rtc_peer_connection(signal_peer.clone(), config).await?;
-
-    let (channel_ready_tx, mut channel_ready_rx) = futures_channel::mpsc::channel(1);
-    let data_channel = create_data_channel(
-        &connection,
-        channel_ready_tx,
-        signal_peer.id.clone(),
-        new_peer_tx,
-        from_peer_message_tx,
-    )
-    .await;
+    /// Reports the peer as connected
+    fn connected(
+        self,
+        signal_peer: SignalPeer,
+        signal_receiver: UnboundedReceiver<PeerSignal>,
+        peer_state_tx: &UnboundedSender<(PeerId, PeerState)>,
+    ) -> Handshake {
+        // The message loop may already be gone if the socket was dropped
+        let _ = peer_state_tx.unbounded_send((signal_peer.id, PeerState::Connected));
+        Handshake {
+            connection: self.connection,
+            data_channels: self.data_channels,
+            signal_receiver,
+            ice_state_rx: self.ice_state_rx,
+        }
+    }
 
-    let offer;
-    loop {
-        match signal_receiver.next().await.ok_or("error")? {
-            PeerSignal::Offer(o) => {
-                offer = o;
-                break;
-            }
-            _ => {
-                warn!("ignoring other signal!!!");
-            }
+    /// Closes a connection that's being replaced, without reporting the peer
+    /// as disconnected
+    async fn discard(self) {
+        for channel in &self.data_channels {
+            channel.on_close(Box::new(|| Box::pin(async {})));
+        }
+        if let Err(e) = self.connection.close().await {
+            warn!("failed to close peer connection: {e:?}");
         }
     }
-    debug!("received offer");
+}
+
+/// Sets the peer's offer as the remote description, and sends it our answer
+async fn answer_offer(
+    connection: &RTCPeerConnection,
+    signal_peer: &SignalPeer,
+    offer: String,
+) -> Result<(), webrtc::Error> {
     let mut remote_description = RTCSessionDescription::default();
     remote_description.sdp = offer;
-    remote_description.sdp_type = RTCSdpType::Offer; // TODO: Or leave unspecified?
+    remote_description.sdp_type = RTCSdpType::Offer;
     connection
         .set_remote_description(remote_description)
         .await?;
 
     let answer = connection.create_answer(None).await?;
     signal_peer.send(PeerSignal::Answer(answer.sdp.clone()));
-    connection.set_local_description(answer).await?;
-    // Can only send candidates after sending the local description.
-    trickle.send_pending_candidates().await;
-    let mut trickle_fut = Box::pin(
-        CandidateTrickle::listen_for_remote_candidates(Arc::clone(&connection), signal_receiver)
-            .fuse(),
-    );
-
-    let mut channel_ready_fut = channel_ready_rx.next();
-    loop {
-        select! {
-            _ = channel_ready_fut => break,
-            // TODO: this means that the signalling is down, should return an
-            // error
-            _ = trickle_fut => continue,
-        };
+    connection.set_local_description(answer).await
+}
+
+async fn add_ice_candidate(connection: &RTCPeerConnection, candidate: String) {
+    debug!("got an IceCandidate signal! {}", candidate);
+    let candidate = RTCIceCandidateInit {
+        candidate,
+        ..Default::default()
+    };
+    if let Err(e) = connection.add_ice_candidate(candidate).await {
+        warn!("failed to add remote ice candidate: {e:?}");
     }
+}
+
+/// Closes the connection of a handshake that can't complete
+async fn abort_handshake(connection: &RTCPeerConnection) -> Box<dyn std::error::Error> {
+    if let Err(e) = connection.close().await {
+        warn!("failed to close peer connection: {e:?}");
+    }
+    "signalling with peer ended in the middle of the handshake".into()
+}
 
-    Ok((signal_peer.id, data_channel, trickle_fut))
+/// Reports that we gave up on connecting to the peer
+fn connection_failed(
+    signal_peer: &SignalPeer,
+    peer_state_tx: &UnboundedSender<(PeerId, PeerState)>,
+) -> Box<dyn std::error::Error> {
+    // The message loop may already be gone if the socket was dropped
+    let _ = peer_state_tx.unbounded_send((signal_peer.id.clone(), PeerState::ConnectionFailed));
+    Box::new(HandshakeTimedOut)
 }
 
+/// Creates a peer connection, along with a receiver for its ICE connection
+/// state changes
 async fn create_rtc_peer_connection(
     signal_peer: SignalPeer,
     config: &WebRtcSocketConfig,
-) -> Result<(Arc<RTCPeerConnection>, Arc<CandidateTrickle>), Box<dyn std::error::Error>> {
+) -> Result<
+    (
+        Arc<RTCPeerConnection>,
+        Arc<CandidateTrickle>,
+        UnboundedReceiver<RTCIceConnectionState>,
+    ),
+    Box<dyn std::error::Error>,
+> {
     let api = APIBuilder::new().build();
 
-    let ice_server = &config.ice_server;
     let config = RTCConfiguration {
-        ice_servers: vec![RTCIceServer {
-            urls: ice_server.urls.clone(),
-            username: ice_server.username.clone().unwrap_or_default(),
-            credential: ice_server.credential.clone().unwrap_or_default(),
-            ..Default::default()
-        }],
+        ice_servers: config
+            .ice_servers
+            .iter()
+            .map(|ice_server| RTCIceServer {
+                urls: ice_server.urls.clone(),
+                username: ice_server.username.clone().unwrap_or_default(),
+                credential: ice_server.credential.clone().unwrap_or_default(),
+                ..Default::default()
+            })
+            .collect(),
         ..Default::default()
     };
 
@@ -413,50 +667,91 @@ async fn create_rtc_peer_connection(
         Box::pin(async {})
     }));
 
-    Ok((connection, trickle))
+    // Watched instead of the peer connection state, which doesn't go back to
+    // connected after an ICE restart
+    let (ice_state_tx, ice_state_rx) = futures_channel::mpsc::unbounded();
+    connection.on_ice_connection_state_change(Box::new(move |s| {
+        debug!("ICE Connection State has changed: {}", s);
+        // Nobody cares anymore if the connection was discarded
+        let _ = ice_state_tx.unbounded_send(s);
+        Box::pin(async {})
+    }));
+
+    Ok((connection, trickle, ice_state_rx))
+}
+
+async fn create_data_channels(
+    connection: &RTCPeerConnection,
+    channel_ready: futures_channel::mpsc::Sender<u8>,
+    peer_id: PeerId,
+    peer_state_tx: UnboundedSender<(PeerId, PeerState)>,
+    from_peer_message_tx: Vec<UnboundedSender<(PeerId, Packet)>>,
+    channel_configs: &[ChannelConfig],
+) -> Result<Vec<Arc<RTCDataChannel>>, webrtc::Error> {
+    let mut channels = vec![];
+    for (i, (channel_config, from_peer_message_tx)) in
+        channel_configs.iter().zip(from_peer_message_tx).enumerate()
+    {
+        let channel = create_data_channel(
+            connection,
+            channel_ready.clone(),
+            peer_id.clone(),
+            peer_state_tx.clone(),
+            from_peer_message_tx,
+            channel_config,
+            i,
+        )
+        .await?;
+        channels.push(channel);
+    }
+    Ok(channels)
 }
 
 async fn create_data_channel(
     connection: &RTCPeerConnection,
     mut channel_ready: futures_channel::mpsc::Sender<u8>,
     peer_id: PeerId,
-    mut new_peer_tx: UnboundedSender<PeerId>,
+    peer_state_tx: UnboundedSender<(PeerId, PeerState)>,
     from_peer_message_tx: UnboundedSender<(PeerId, Packet)>,
-) -> Arc<RTCDataChannel> {
+    channel_config: &ChannelConfig,
+    channel_index: usize,
+) -> Result<Arc<RTCDataChannel>, webrtc::Error> {
     let config = RTCDataChannelInit {
-        ordered: Some(false),
-        max_retransmits: Some(0),
-        negotiated: Some(DATA_CHANNEL_ID),
+        ordered: Some(channel_config.ordered),
+        max_retransmits: channel_config.max_retransmits,
+        max_packet_life_time: channel_config.max_packet_lifetime,
+        negotiated: Some(DATA_CHANNEL_ID + channel_index as u16),
         ..Default::default()
     };
 
     let channel = connection
-        .create_data_channel("webudp", Some(config))
-        .await
-        .unwrap();
+        .create_data_channel(&format!("matchbox_socket_{channel_index}"), Some(config))
+        .await?;
 
-    let peer_id2 = peer_id.clone();
     channel.on_open(Box::new(move || {
         debug!("Data channel ready");
         Box::pin(async move {
-            new_peer_tx.send(peer_id2.clone()).await.unwrap();
-            channel_ready.try_send(1).unwrap();
+            // The handshake may already have been abandoned
+            let _ = channel_ready.try_send(1);
         })
     }));
 
-    setup_data_channel(&channel, peer_id, from_peer_message_tx).await;
+    setup_data_channel(&channel, peer_id, peer_state_tx, from_peer_message_tx).await;
 
-    channel
+    Ok(channel)
 }
 
 async fn setup_data_channel(
     data_channel: &RTCDataChannel,
     peer_id: PeerId,
+    peer_state_tx: UnboundedSender<(PeerId, PeerState)>,
     from_peer_message_tx: UnboundedSender<(PeerId, Packet)>,
 ) {
+    let peer_id2 = peer_id.clone();
     data_channel.on_close(Box::new(move || {
-        // TODO: handle this somehow
         debug!("Data channel closed");
+        // The message loop may already be gone if the socket was dropped
+        let _ = peer_state_tx.unbounded_send((peer_id2.clone(), PeerState::Disconnected));
         Box::pin(async move {})
     }));
 
@@ -469,47 +764,517 @@ async fn setup_data_channel(
     data_channel.on_message(Box::new(move |message| {
         let packet = (*message.data).into();
         debug!("rx {:?}", packet);
-        from_peer_message_tx
-            .unbounded_send((peer_id.clone(), packet))
-            .unwrap();
+        // The socket may have been dropped, in which case nobody cares
+        let _ = from_peer_message_tx.unbounded_send((peer_id.clone(), packet));
         Box::pin(async move {})
     }));
 }
 
+/// Runs the handshake with a peer, and then sends it messages until it's gone
+///
+/// If the connection fails, ICE is restarted. The impolite peer sends the
+/// restart offer, so they don't collide.
 async fn peer_loop(
-    handshake_fut: impl Future<
-        Output = Result<
-            (
-                PeerId,
-                Arc<RTCDataChannel>,
-                Pin<Box<dyn FusedFuture<Output = Result<(), Box<dyn std::error::Error>>> + Send>>,
-            ),
-            Box<dyn std::error::Error>,
-        >,
-    >,
-    mut to_peer_message_rx: UnboundedReceiver<Packet>,
+    signal_peer: SignalPeer,
+    polite: bool,
+    handshake_fut: impl Future<Output = HandshakeResult>,
+    mut to_peer_message_rx: UnboundedReceiver<(usize, Bytes)>,
+    peer_state_tx: UnboundedSender<(PeerId, PeerState)>,
+    peer_stats_tx: UnboundedSender<(PeerId, PeerStats)>,
+    config: &WebRtcSocketConfig,
 ) {
-    let (_peer_id, data_channel, mut trickle_fut) = handshake_fut.await.unwrap();
+    let peer_id = signal_peer.id.clone();
+    let Handshake {
+        connection,
+        data_channels,
+        mut signal_receiver,
+        mut ice_state_rx,
+    } = match handshake_fut.await {
+        Ok(handshake) => handshake,
+        // Already reported as a failed connection
+        Err(e) if e.is::<HandshakeTimedOut>() => return,
+        Err(e) => {
+            // Only fails the connection to this peer, e.g. when it sent an
+            // offer we can't use. The message loop ignores it if the peer
+            // already left, which aborts the handshake.
+            warn!("handshake with peer {peer_id} failed: {e}");
+            // The socket may have been dropped, in which case nobody cares
+            let _ = peer_state_tx.unbounded_send((peer_id, PeerState::ConnectionFailed));
+            return;
+        }
+    };
 
+    let stats_peer_id = peer_id.clone();
     let message_loop_fut = async move {
-        while let Some(message) = to_peer_message_rx.next().await {
+        while let Some((channel, message)) = to_peer_message_rx.next().await {
             debug!("tx {:?}", message);
-            let message = message.clone();
-            let message = Bytes::from(message);
-            data_channel.send(&message).await.unwrap();
+            if let Err(e) = data_channels[channel].send(&message).await {
+                // This likely means the peer disconnected, which is reported
+                // when the data channel closes.
+                warn!("failed to send to peer {peer_id}: {e:?}");
+            }
         }
     };
     let message_loop_fut = message_loop_fut.fuse();
     pin_mut!(message_loop_fut);
 
+    let mut stats_timeout = Delay::new(Duration::from_millis(STATS_INTERVAL)).fuse();
+    // Running while an ICE restart is in progress
+    let mut restart_timeout = Fuse::terminated();
+
     loop {
         select! {
             _ = message_loop_fut => break,
-            // TODO: this means that the signalling is down, should return an
-            // error
-            _ = trickle_fut => continue,
+            // Stops once signalling with the peer ends, i.e. it left
+            signal = signal_receiver.select_next_some() => {
+                handle_signal(&connection, &signal_peer, signal).await;
+                continue;
+            }
+            state = ice_state_rx.select_next_some() => {
+                match state {
+                    RTCIceConnectionState::Disconnected | RTCIceConnectionState::Failed
+                        if restart_timeout.is_terminated() =>
+                    {
+                        debug!("connection to {} is {state}, restarting ice", signal_peer.id);
+                        restart_timeout = Delay::new(config.handshake.timeout).fuse();
+                        if !polite {
+                            if let Err(e) = restart_ice(&connection, &signal_peer).await {
+                                warn!("failed to restart ice: {e:?}");
+                            }
+                        }
+                    }
+                    RTCIceConnectionState::Connected | RTCIceConnectionState::Completed => {
+                        restart_timeout = Fuse::terminated()
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            _ = restart_timeout => {
+                warn!("failed to restart ice with {}, disconnecting", signal_peer.id);
+                // The socket may have been dropped, in which case nobody cares
+                let _ = peer_state_tx.unbounded_send((signal_peer.id.clone(), PeerState::Disconnected));
+                break;
+            }
+            _ = stats_timeout => {}
+        }
+
+        // Time to refresh the stats
+        let stats = peer_stats(&connection).await;
+        // The socket may have been dropped, in which case nobody cares
+        let _ = peer_stats_tx.unbounded_send((stats_peer_id.clone(), stats));
+        stats_timeout = Delay::new(Duration::from_millis(STATS_INTERVAL)).fuse();
+    }
+
+    // The peer left, disconnected, or the socket was dropped
+    if let Err(e) = connection.close().await {
+        warn!("failed to close peer connection: {e:?}");
+    }
+}
+
+/// Handles a signal from a peer after the handshake
+async fn handle_signal(
+    connection: &RTCPeerConnection,
+    signal_peer: &SignalPeer,
+    signal: PeerSignal,
+) {
+    let res = match signal {
+        PeerSignal::IceCandidate(candidate) => {
+            add_ice_candidate(connection, candidate).await;
+            Ok(())
+        }
+        PeerSignal::Offer(_)
+            if connection.signaling_state() == RTCSignalingState::HaveLocalOffer =>
+        {
+            // Only the impolite peer restarts ICE, so this is from an earlier
+            // handshake attempt
+            debug!("ignoring offer colliding with our ice restart");
+            Ok(())
+        }
+        PeerSignal::Offer(offer) => {
+            debug!("{} is restarting ice", signal_peer.id);
+            answer_offer(connection, signal_peer, offer).await
+        }
+        PeerSignal::Answer(answer) => {
+            let mut remote_description = RTCSessionDescription::default();
+            remote_description.sdp = answer;
+            remote_description.sdp_type = RTCSdpType::Answer;
+            connection.set_remote_description(remote_description).await
+        }
+    };
+    if let Err(e) = res {
+        warn!("failed to handle signal from {}: {e:?}", signal_peer.id);
+    }
+}
+
+/// Sends the peer a new offer with fresh ICE credentials, so a new route
+/// between us can be found
+async fn restart_ice(
+    connection: &RTCPeerConnection,
+    signal_peer: &SignalPeer,
+) -> Result<(), webrtc::Error> {
+    let options = RTCOfferOptions {
+        ice_restart: true,
+        ..Default::default()
+    };
+    let offer = connection.create_offer(Some(options)).await?;
+    // Before setting it, as the new candidates are sent as soon as they're
+    // gathered
+    signal_peer.send(PeerSignal::Offer(offer.sdp.clone()));
+    connection.set_local_description(offer).await
+}
+
+async fn peer_stats(connection: &RTCPeerConnection) -> PeerStats {
+    let reports = connection.get_stats().await.reports;
+    let mut stats = PeerStats::default();
+    for report in reports.values() {
+        match report {
+            StatsReportType::DataChannel(channel) => {
+                stats.bytes_sent += channel.bytes_sent as u64;
+                stats.bytes_received += channel.bytes_received as u64;
+                stats.packets_sent += channel.messages_sent as u64;
+                stats.packets_received += channel.messages_received as u64;
+            }
+            StatsReportType::CandidatePair(pair) if pair.nominated => {
+                stats.local_candidate_type = candidate_type(reports.get(&pair.local_candidate_id));
+                stats.remote_candidate_type =
+                    candidate_type(reports.get(&pair.remote_candidate_id));
+                // Both are zero until they've been measured
+                if pair.current_round_trip_time > 0.0 {
+                    stats.round_trip_time =
+                        Some(Duration::from_secs_f64(pair.current_round_trip_time));
+                    stats.retransmissions = Some(pair.retransmissions_sent);
+                }
+            }
+            _ => {}
+        }
+    }
+    stats
+}
+
+fn candidate_type(report: Option<&StatsReportType>) -> Option<CandidateType> {
+    let candidate = match report? {
+        StatsReportType::LocalCandidate(candidate)
+        | StatsReportType::RemoteCandidate(candidate) => candidate,
+        _ => return None,
+    };
+    match candidate.candidate_type {
+        IceCandidateType::Host => Some(CandidateType::Host),
+        IceCandidateType::ServerReflexive => Some(CandidateType::ServerReflexive),
+        IceCandidateType::PeerReflexive => Some(CandidateType::PeerReflexive),
+        IceCandidateType::Relay => Some(CandidateType::Relay),
+        IceCandidateType::Unspecified => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, pin_mut, select, Future, FutureExt, StreamExt};
+    use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+    use futures_timer::Delay;
+    use std::time::Duration;
+
+    use super::message_loop;
+    use crate::webrtc_socket::{
+        messages::{PeerEvent, PeerId, PeerRequest},
+        CandidateType, HandshakePolicy, PeerSignal, PeerState, PeerStats, SocketError,
+        WebRtcSocketConfig,
+    };
+
+    /// A message loop, with the signalling server left to the test
+    struct TestPeer {
+        events_tx: UnboundedSender<PeerEvent>,
+        requests_rx: UnboundedReceiver<PeerRequest>,
+        peer_state_rx: UnboundedReceiver<(PeerId, PeerState)>,
+        peer_stats_rx: UnboundedReceiver<(PeerId, PeerStats)>,
+        message_loop: future::BoxFuture<'static, Result<(), SocketError>>,
+        // Dropping these would end the message loop
+        _keep_alive: Box<dyn Send>,
+    }
+
+    fn config() -> WebRtcSocketConfig {
+        WebRtcSocketConfig {
+            // Peers in the same process can reach each other without STUN
+            ice_servers: vec![],
+            ..Default::default()
+        }
+    }
+
+    impl TestPeer {
+        fn new(id: &str, config: WebRtcSocketConfig) -> Self {
+            let (id_tx, id_rx) = unbounded();
+            let (host_tx, host_rx) = unbounded();
+            let (requests_tx, requests_rx) = unbounded();
+            let (events_tx, events_rx) = unbounded();
+            let (messages_out_tx, messages_out_rx) = unbounded();
+            let (peer_state_tx, peer_state_rx) = unbounded();
+            let (peer_stats_tx, peer_stats_rx) = unbounded();
+            let (messages_from_peers_tx, messages_from_peers_rx) = unbounded();
+            events_tx
+                .unbounded_send(PeerEvent::IdAssigned(id.to_string()))
+                .unwrap();
+            TestPeer {
+                events_tx,
+                requests_rx,
+                peer_state_rx,
+                peer_stats_rx,
+                message_loop: message_loop(
+                    id_tx,
+                    host_tx,
+                    config,
+                    requests_tx,
+                    events_rx,
+                    messages_out_rx,
+                    peer_state_tx,
+                    peer_stats_tx,
+                    vec![messages_from_peers_tx],
+                )
+                .boxed(),
+                _keep_alive: Box::new((id_rx, host_rx, messages_out_tx, messages_from_peers_rx)),
+            }
+        }
+    }
+
+    /// Relays the signals `sender` sends to `receiver`, like the server would,
+    /// except for the first `drop` signals, which are lost
+    async fn relay(
+        sender: PeerId,
+        mut requests_rx: UnboundedReceiver<PeerRequest>,
+        events_tx: UnboundedSender<PeerEvent>,
+        mut drop: usize,
+    ) {
+        while let Some(request) = requests_rx.next().await {
+            if let PeerRequest::Signal { data, .. } = request {
+                if drop > 0 {
+                    drop -= 1;
+                    continue;
+                }
+                let _ = events_tx.unbounded_send(PeerEvent::Signal {
+                    sender: sender.clone(),
+                    data,
+                });
+            }
         }
     }
 
-    // TODO: clear on_message?
+    async fn wait_for_state(
+        peer_state_rx: &mut UnboundedReceiver<(PeerId, PeerState)>,
+        expected: (&str, PeerState),
+    ) {
+        let (peer, state) = peer_state_rx.next().await.expect("peer state");
+        assert_eq!((peer.as_str(), state), expected);
+    }
+
+    /// Both peers offer at once, and the polite one answers instead
+    #[test]
+    fn offer_collision() {
+        let a = TestPeer::new("a", config());
+        let b = TestPeer::new("b", config());
+        a.events_tx
+            .unbounded_send(PeerEvent::NewPeer("b".to_string()))
+            .unwrap();
+        b.events_tx
+            .unbounded_send(PeerEvent::NewPeer("a".to_string()))
+            .unwrap();
+
+        connect(a, b, 0);
+    }
+
+    /// The first offer is lost, so the handshake times out and is retried
+    #[test]
+    fn handshake_retry() {
+        let config = || WebRtcSocketConfig {
+            handshake: HandshakePolicy {
+                // Long enough for ICE to finish while other tests are running
+                timeout: Duration::from_secs(3),
+                max_retries: 1,
+            },
+            ..config()
+        };
+        let a = TestPeer::new("a", config());
+        let b = TestPeer::new("b", config());
+        a.events_tx
+            .unbounded_send(PeerEvent::NewPeer("b".to_string()))
+            .unwrap();
+
+        connect(a, b, 1);
+    }
+
+    /// Losing the signalling server doesn't end the message loop
+    #[test]
+    fn signalling_closed() {
+        let a = TestPeer::new("a", config());
+        drop(a.events_tx);
+
+        let message_loop = a.message_loop.fuse();
+        let timeout = Delay::new(Duration::from_millis(500)).fuse();
+        pin_mut!(message_loop, timeout);
+        futures::executor::block_on(async {
+            select! {
+                res = message_loop => panic!("message loop ended: {:?}", res),
+                _ = timeout => {}
+            }
+        });
+    }
+
+    /// An offer we can't use only fails the connection to the peer that sent it
+    #[test]
+    fn malformed_offer() {
+        let mut a = TestPeer::new("a", config());
+        a.events_tx
+            .unbounded_send(PeerEvent::Signal {
+                sender: "c".to_string(),
+                data: PeerSignal::Offer("not an offer".to_string()),
+            })
+            .unwrap();
+
+        let mut timeout = Delay::new(Duration::from_secs(10)).fuse();
+        futures::executor::block_on(async {
+            select! {
+                state = a.peer_state_rx.select_next_some() => {
+                    assert_eq!(state, ("c".to_string(), PeerState::ConnectionFailed));
+                }
+                res = (&mut a.message_loop).fuse() => panic!("message loop ended: {:?}", res),
+                _ = timeout => panic!("handshake didn't fail"),
+            }
+        });
+
+        // Other peers can still connect
+        let b = TestPeer::new("b", config());
+        a.events_tx
+            .unbounded_send(PeerEvent::NewPeer("b".to_string()))
+            .unwrap();
+        connect(a, b, 0);
+    }
+
+    /// Relays signals between the peers until they're connected to each
+    /// other, dropping the first `drop` signals from `a`
+    fn connect(a: TestPeer, b: TestPeer, drop: usize) {
+        connect_then(a, b, drop, |_| future::ready(()));
+    }
+
+    /// Like [`connect`], but keeps the peers connected until `then`
+    /// completes, given the stats `a` reports
+    fn connect_then<F: Future<Output = ()>>(
+        a: TestPeer,
+        b: TestPeer,
+        drop: usize,
+        then: impl FnOnce(UnboundedReceiver<(PeerId, PeerStats)>) -> F,
+    ) {
+        let signalling = future::join(
+            relay("a".to_string(), a.requests_rx, b.events_tx, drop),
+            relay("b".to_string(), b.requests_rx, a.events_tx, 0),
+        );
+        let (mut a_state_rx, mut b_state_rx) = (a.peer_state_rx, b.peer_state_rx);
+        let a_stats_rx = a.peer_stats_rx;
+        let connected = async move {
+            future::join(
+                wait_for_state(&mut a_state_rx, ("b", PeerState::Connected)),
+                wait_for_state(&mut b_state_rx, ("a", PeerState::Connected)),
+            )
+            .await;
+            then(a_stats_rx).await;
+        };
+        let message_loops = future::try_join(a.message_loop, b.message_loop).fuse();
+        let signalling = signalling.fuse();
+        let connected = connected.fuse();
+        let timeout = Delay::new(Duration::from_secs(30)).fuse();
+        pin_mut!(message_loops, signalling, connected, timeout);
+
+        futures::executor::block_on(async {
+            select! {
+                _ = connected => {}
+                res = message_loops => panic!("message loop ended: {:?}", res),
+                _ = signalling => panic!("signalling ended"),
+                _ = timeout => panic!("timed out"),
+            }
+        });
+    }
+
+    /// Connected peers report how they're connected
+    #[test]
+    fn stats_candidate_types() {
+        let a = TestPeer::new("a", config());
+        let b = TestPeer::new("b", config());
+        a.events_tx
+            .unbounded_send(PeerEvent::NewPeer("b".to_string()))
+            .unwrap();
+
+        connect_then(a, b, 0, |mut peer_stats_rx| async move {
+            let (peer, stats) = peer_stats_rx.next().await.expect("stats");
+            assert_eq!(peer, "b");
+            assert_eq!(stats.local_candidate_type, Some(CandidateType::Host));
+            // Peer reflexive if a connectivity check beat the candidate here
+            assert!(matches!(
+                stats.remote_candidate_type,
+                Some(CandidateType::Host | CandidateType::PeerReflexive)
+            ));
+        });
+    }
+
+    /// Connected peers report the round trip time to each other
+    #[test]
+    #[ignore = "webrtc-ice 0.9 doesn't measure round trip times yet"]
+    fn stats_round_trip_time() {
+        let a = TestPeer::new("a", config());
+        let b = TestPeer::new("b", config());
+        a.events_tx
+            .unbounded_send(PeerEvent::NewPeer("b".to_string()))
+            .unwrap();
+
+        connect_then(a, b, 0, |mut peer_stats_rx| async move {
+            loop {
+                let (peer, stats) = peer_stats_rx.next().await.expect("stats");
+                assert_eq!(peer, "b");
+                if let Some(round_trip_time) = stats.round_trip_time {
+                    assert!(round_trip_time < Duration::from_secs(1));
+                    assert!(stats.retransmissions.is_some());
+                    break;
+                }
+            }
+        });
+    }
+
+    /// A peer that never answers is given up on, without failing the socket
+    #[test]
+    fn handshake_timeout() {
+        let a = TestPeer::new(
+            "a",
+            WebRtcSocketConfig {
+                handshake: HandshakePolicy {
+                    timeout: Duration::from_millis(100),
+                    max_retries: 1,
+                },
+                ..config()
+            },
+        );
+        a.events_tx
+            .unbounded_send(PeerEvent::NewPeer("b".to_string()))
+            .unwrap();
+
+        let mut offers = 0;
+        let mut requests = a.requests_rx.fuse();
+        let mut peer_state_rx = a.peer_state_rx;
+        let mut message_loop = a.message_loop.fuse();
+        let mut timeout = Delay::new(Duration::from_secs(10)).fuse();
+        futures::executor::block_on(async {
+            loop {
+                select! {
+                    request = requests.select_next_some() => {
+                        if let PeerRequest::Signal { data: PeerSignal::Offer(_), .. } = request {
+                            offers += 1;
+                        }
+                    }
+                    state = peer_state_rx.select_next_some() => {
+                        assert_eq!(state, ("b".to_string(), PeerState::ConnectionFailed));
+                        break;
+                    }
+                    res = message_loop => panic!("message loop ended: {:?}", res),
+                    _ = timeout => panic!("handshake didn't time out"),
+                }
+            }
+        });
+        // The first attempt, and one retry
+        assert_eq!(offers, 2);
+    }
 }
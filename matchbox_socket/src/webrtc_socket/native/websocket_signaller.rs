@@ -0,0 +1,120 @@
+use async_tungstenite::{
+    async_std::{connect_async, ConnectStream},
+    tungstenite::{
+        client::IntoClientRequest,
+        http::{header::AUTHORIZATION, HeaderValue},
+        Message,
+    },
+    WebSocketStream,
+};
+use futures::{SinkExt, StreamExt};
+use log::{debug, warn};
+
+use crate::webrtc_socket::{
+    messages::{PeerEvent, PeerRequest},
+    signaller::{connection_url, new_resume_token, Signaller, SignallerFuture},
+    SocketError,
+};
+
+/// Signals with a matchbox server over a websocket
+#[derive(Debug)]
+pub struct WebSocketSignaller {
+    room_url: String,
+    auth_token: Option<String>,
+    /// Lets us keep our id when we reconnect
+    resume_token: String,
+    wsio: Option<WebSocketStream<ConnectStream>>,
+}
+
+impl WebSocketSignaller {
+    pub fn new(room_url: String, auth_token: Option<String>) -> Self {
+        Self {
+            room_url,
+            auth_token,
+            resume_token: new_resume_token(),
+            wsio: None,
+        }
+    }
+
+    fn wsio(&mut self) -> Result<&mut WebSocketStream<ConnectStream>, SocketError> {
+        self.wsio
+            .as_mut()
+            .ok_or_else(|| SocketError::Signalling("not connected".to_string()))
+    }
+}
+
+impl Signaller for WebSocketSignaller {
+    fn connect(&mut self) -> SignallerFuture<'_, ()> {
+        Box::pin(async move {
+            // Drop any previous connection first
+            self.wsio = None;
+            let mut request = connection_url(&self.room_url, &self.resume_token)
+                .into_client_request()
+                .map_err(|e| SocketError::ConnectionFailed(e.to_string()))?;
+            if let Some(token) = &self.auth_token {
+                let header = HeaderValue::from_str(&format!("Bearer {token}"))
+                    .map_err(|e| SocketError::ConnectionFailed(e.to_string()))?;
+                request.headers_mut().insert(AUTHORIZATION, header);
+            }
+            let (wsio, _response) = connect_async(request)
+                .await
+                .map_err(|e| SocketError::ConnectionFailed(e.to_string()))?;
+            self.wsio = Some(wsio);
+            Ok(())
+        })
+    }
+
+    fn send(&mut self, request: PeerRequest) -> SignallerFuture<'_, ()> {
+        Box::pin(async move {
+            let request = serde_json::to_string(&request).expect("serializing request");
+            debug!("-> {}", request);
+            self.wsio()?
+                .send(Message::Text(request))
+                .await
+                .map_err(|e| SocketError::Signalling(e.to_string()))
+        })
+    }
+
+    fn next_event(&mut self) -> SignallerFuture<'_, PeerEvent> {
+        Box::pin(async move {
+            let wsio = self.wsio()?;
+            loop {
+                match wsio.next().await {
+                    Some(Ok(Message::Text(message))) => {
+                        debug!("{}", message);
+                        return serde_json::from_str(&message).map_err(|e| {
+                            SocketError::Signalling(format!(
+                                "couldn't parse peer event: {}.\nEvent: {}",
+                                e, message
+                            ))
+                        });
+                    }
+                    Some(Ok(message)) => {
+                        warn!(
+                            "ignoring unexpected non-text message from signalling server: {:?}",
+                            message
+                        )
+                    }
+                    Some(Err(e)) => {
+                        return Err(SocketError::Signalling(e.to_string()));
+                    }
+                    None => {
+                        debug!("Disconnected from signalling server");
+                        return Err(SocketError::Signalling(
+                            "connection closed by signalling server".to_string(),
+                        ));
+                    }
+                }
+            }
+        })
+    }
+
+    fn close(&mut self) -> SignallerFuture<'_, ()> {
+        Box::pin(async move {
+            self.wsio()?
+                .close(None)
+                .await
+                .map_err(|e| SocketError::Signalling(e.to_string()))
+        })
+    }
+}
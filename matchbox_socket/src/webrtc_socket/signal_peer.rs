@@ -1,4 +1,5 @@
 use futures_channel::mpsc::UnboundedSender;
+use log::warn;
 
 use super::{PeerId, PeerRequest, PeerSignal};
 
@@ -14,7 +15,12 @@ impl SignalPeer {
             receiver: self.id.clone(),
             data: signal,
         };
-        self.sender.unbounded_send(req).expect("Send error");
+        if self.sender.unbounded_send(req).is_err() {
+            warn!(
+                "failed to send signal to {}, signalling loop is gone",
+                self.id
+            );
+        }
     }
 
     pub fn new(id: PeerId, sender: UnboundedSender<PeerRequest>) -> Self {
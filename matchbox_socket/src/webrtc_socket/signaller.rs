@@ -0,0 +1,509 @@
+use futures::{future::Either, FutureExt, StreamExt};
+use futures_timer::Delay;
+use futures_util::select;
+use log::{debug, warn};
+
+use crate::webrtc_socket::{
+    messages::{PeerEvent, PeerRequest, Topology, PROTOCOL_VERSION},
+    ReconnectPolicy, SocketError,
+};
+
+/// The future returned by [`Signaller`] methods
+#[cfg(not(target_arch = "wasm32"))]
+pub type SignallerFuture<'a, T> = futures::future::BoxFuture<'a, Result<T, SocketError>>;
+/// The future returned by [`Signaller`] methods
+#[cfg(target_arch = "wasm32")]
+pub type SignallerFuture<'a, T> = futures::future::LocalBoxFuture<'a, Result<T, SocketError>>;
+
+/// Futures need to be `Send` on native, so the message loop future can be
+/// spawned on a multi-threaded executor.
+#[cfg(not(target_arch = "wasm32"))]
+pub trait MaybeSend: Send {}
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: Send> MaybeSend for T {}
+#[cfg(target_arch = "wasm32")]
+pub trait MaybeSend {}
+#[cfg(target_arch = "wasm32")]
+impl<T> MaybeSend for T {}
+
+/// A transport for exchanging [`PeerRequest`]s and [`PeerEvent`]s with a
+/// signalling service
+///
+/// The default implementation talks to a `matchbox_server` over a
+/// websocket, see [`WebRtcSocketConfig::room_url`]. Implement this trait to
+/// signal over a different transport, and use it with
+/// [`WebRtcSocket::new_with_signaller`].
+///
+/// [`WebRtcSocketConfig::room_url`]: crate::WebRtcSocketConfig::room_url
+/// [`WebRtcSocket::new_with_signaller`]: crate::WebRtcSocket::new_with_signaller
+pub trait Signaller: MaybeSend + 'static {
+    /// Connect to the signalling service
+    ///
+    /// Once connected, the service must assign us an id by sending
+    /// [`PeerEvent::IdAssigned`] before any other event.
+    ///
+    /// Called once when the socket starts, and again each time the socket
+    /// reconnects after an error, as configured by
+    /// [`WebRtcSocketConfig::reconnect`]. Fail with
+    /// [`SocketError::ConnectionFailed`] if the service can't be reached.
+    ///
+    /// [`WebRtcSocketConfig::reconnect`]: crate::WebRtcSocketConfig::reconnect
+    fn connect(&mut self) -> SignallerFuture<'_, ()>;
+
+    /// Send a request to the signalling service
+    fn send(&mut self, request: PeerRequest) -> SignallerFuture<'_, ()>;
+
+    /// Wait for the next event from the signalling service
+    ///
+    /// Fail if the connection is lost. [`PeerEvent::Error`] ends the
+    /// connection as well. The returned future may be dropped
+    /// before it completes, in which case no event must be lost.
+    fn next_event(&mut self) -> SignallerFuture<'_, PeerEvent>;
+
+    /// Leave the signalling service
+    ///
+    /// Called once the socket is dropped, so the service can tell the other
+    /// peers we left on purpose, rather than wait for us to reconnect. Does
+    /// nothing by default.
+    fn close(&mut self) -> SignallerFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Runs the signalling loop, reconnecting the signaller according to the
+/// given [`ReconnectPolicy`] whenever the connection is lost.
+///
+/// The request and event queues outlive each connection, so handshakes in
+/// progress pick up where they left off once we're reconnected.
+pub(crate) async fn reconnecting_signalling_loop<S: Signaller>(
+    mut signaller: S,
+    policy: ReconnectPolicy,
+    mut requests_receiver: futures_channel::mpsc::UnboundedReceiver<PeerRequest>,
+    events_sender: futures_channel::mpsc::UnboundedSender<PeerEvent>,
+) -> Result<(), SocketError> {
+    let mut attempt = 0;
+    loop {
+        let err =
+            match signalling_loop(&mut signaller, &mut requests_receiver, &events_sender).await {
+                // The message loop is gone, no need to stay connected
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+        if matches!(
+            err,
+            SocketError::RoomFull | SocketError::VersionMismatch { .. }
+        ) {
+            // Neither the room nor the server will change any time soon
+            return Err(err);
+        }
+        if !matches!(err, SocketError::ConnectionFailed(_)) {
+            // We did connect successfully, so start counting from scratch
+            attempt = 0;
+        }
+        if attempt >= policy.max_attempts {
+            return Err(err);
+        }
+        let delay = policy.delay(attempt);
+        attempt += 1;
+        warn!(
+            "lost connection to signalling server: {err}, reconnecting in {delay:?} (attempt {attempt}/{})",
+            policy.max_attempts
+        );
+        Delay::new(delay).await;
+    }
+}
+
+/// Connects the signaller and relays requests and events until the
+/// connection is lost.
+///
+/// Returns `Ok(())` only if the message loop is gone.
+async fn signalling_loop<S: Signaller>(
+    signaller: &mut S,
+    requests_receiver: &mut futures_channel::mpsc::UnboundedReceiver<PeerRequest>,
+    events_sender: &futures_channel::mpsc::UnboundedSender<PeerEvent>,
+) -> Result<(), SocketError> {
+    debug!("Signalling loop started");
+    signaller.connect().await?;
+
+    loop {
+        // The event future borrows the signaller, so make sure it's dropped
+        // before we send anything
+        let next = {
+            let next_event = signaller.next_event().fuse();
+            futures::pin_mut!(next_event);
+            select! {
+                request = requests_receiver.next() => Either::Left(request),
+                event = next_event => Either::Right(event),
+            }
+        };
+
+        match next {
+            Either::Left(Some(request)) => signaller.send(request).await?,
+            // The message loop is gone, nothing more to signal
+            Either::Left(None) => {
+                leave(signaller).await;
+                return Ok(());
+            }
+            Either::Right(event) => {
+                let event = match event? {
+                    PeerEvent::Error(error) => return Err(error.into()),
+                    event => event,
+                };
+                if events_sender.unbounded_send(event).is_err() {
+                    // The message loop is gone, nobody is listening
+                    leave(signaller).await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Closes the signaller, we're done with it either way
+async fn leave<S: Signaller>(signaller: &mut S) {
+    if let Err(e) = signaller.close().await {
+        debug!("error closing the connection to the signalling server: {e}");
+    }
+}
+
+/// A random secret to reconnect to a matchbox server with, so we keep our id
+/// and the peers we're connected to don't think we left
+pub(crate) fn new_resume_token() -> String {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes).expect("failed to get random bytes");
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The url to connect to a matchbox server with
+///
+/// Tells the server the protocol version we speak, so it can turn us away if
+/// it speaks a different one, and the token to resume with.
+pub(crate) fn connection_url(room_url: &str, resume_token: &str) -> String {
+    let url = with_query_param(room_url, "v", &PROTOCOL_VERSION.to_string());
+    with_query_param(&url, "resume", resume_token)
+}
+
+/// Asks the matchbox server for a room with the given topology
+///
+/// Mesh rooms are the server's default, so they're left out of the url.
+pub(crate) fn with_topology(room_url: &str, topology: Topology) -> String {
+    match topology {
+        Topology::Mesh => room_url.to_string(),
+        Topology::Star => with_query_param(room_url, "topology", "star"),
+    }
+}
+
+/// Appends a query parameter to a url that may have a query already
+///
+/// The value is not escaped, so it must be url-safe.
+pub(crate) fn with_query_param(url: &str, key: &str, value: &str) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{url}{separator}{key}={value}")
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, FutureExt, StreamExt};
+    use futures_channel::mpsc::unbounded;
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use super::{
+        connection_url, new_resume_token, reconnecting_signalling_loop, with_query_param,
+        with_topology, Signaller, SignallerFuture,
+    };
+    use crate::webrtc_socket::{
+        messages::{PeerEvent, PeerRequest, ServerError, Topology, PROTOCOL_VERSION},
+        ReconnectPolicy, SocketError,
+    };
+
+    /// What happens when the fake signaller connects
+    enum Outcome {
+        /// The service can't be reached
+        Unreachable,
+        /// We get an id, and then lose the connection
+        Lost,
+        /// The service turns us away
+        RoomFull,
+        /// The service speaks a different protocol version
+        VersionMismatch,
+        /// We get an id, and stay connected
+        Connected,
+    }
+
+    /// A signaller that goes through the given outcomes, one per connection
+    struct FakeSignaller {
+        outcomes: VecDeque<Outcome>,
+        events: VecDeque<Result<PeerEvent, SocketError>>,
+        /// When each connection attempt was made
+        attempts: Arc<Mutex<Vec<Instant>>>,
+        closed: Arc<Mutex<bool>>,
+    }
+
+    impl Signaller for FakeSignaller {
+        fn connect(&mut self) -> SignallerFuture<'_, ()> {
+            self.attempts.lock().unwrap().push(Instant::now());
+            let outcome = self.outcomes.pop_front().expect("no more outcomes");
+            self.events = match outcome {
+                Outcome::Unreachable => {
+                    return future::err(SocketError::ConnectionFailed("unreachable".into())).boxed()
+                }
+                Outcome::Lost => vec![
+                    Ok(PeerEvent::IdAssigned("lost".into())),
+                    Err(SocketError::Signalling("lost".into())),
+                ],
+                Outcome::RoomFull => vec![Ok(PeerEvent::Error(ServerError::RoomFull))],
+                Outcome::VersionMismatch => {
+                    vec![Ok(PeerEvent::Error(ServerError::VersionMismatch {
+                        server: PROTOCOL_VERSION + 1,
+                    }))]
+                }
+                Outcome::Connected => vec![Ok(PeerEvent::IdAssigned("connected".into()))],
+            }
+            .into();
+            future::ok(()).boxed()
+        }
+
+        fn send(&mut self, _request: PeerRequest) -> SignallerFuture<'_, ()> {
+            future::ok(()).boxed()
+        }
+
+        fn next_event(&mut self) -> SignallerFuture<'_, PeerEvent> {
+            async move {
+                match self.events.pop_front() {
+                    Some(event) => event,
+                    None => future::pending().await,
+                }
+            }
+            .boxed()
+        }
+
+        fn close(&mut self) -> SignallerFuture<'_, ()> {
+            *self.closed.lock().unwrap() = true;
+            future::ok(()).boxed()
+        }
+    }
+
+    fn policy(max_attempts: u32) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts,
+            initial_delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(50),
+        }
+    }
+
+    /// Runs the signalling loop until it ends, or we're connected for good
+    ///
+    /// Returns the result of the loop, and the time between connection
+    /// attempts.
+    fn run(
+        outcomes: Vec<Outcome>,
+        policy: ReconnectPolicy,
+    ) -> (Result<(), SocketError>, Vec<Duration>) {
+        let attempts = Arc::new(Mutex::new(vec![]));
+        let signaller = FakeSignaller {
+            outcomes: outcomes.into(),
+            events: VecDeque::new(),
+            attempts: attempts.clone(),
+            closed: Default::default(),
+        };
+        let (requests_sender, requests_receiver) = unbounded();
+        let (events_sender, mut events_receiver) = unbounded();
+
+        let signalling_loop =
+            reconnecting_signalling_loop(signaller, policy, requests_receiver, events_sender);
+        let connected = async move {
+            while let Some(event) = events_receiver.next().await {
+                if event == PeerEvent::IdAssigned("connected".into()) {
+                    break;
+                }
+            }
+            // Ends the signalling loop
+            drop(requests_sender);
+            future::pending::<()>().await
+        };
+        let res = futures::executor::block_on(async {
+            futures::select! {
+                res = signalling_loop.fuse() => res,
+                _ = connected.fuse() => unreachable!(),
+            }
+        });
+
+        let attempts = attempts.lock().unwrap();
+        let gaps = attempts.windows(2).map(|w| w[1] - w[0]).collect();
+        (res, gaps)
+    }
+
+    #[test]
+    fn delay() {
+        let policy = ReconnectPolicy {
+            max_attempts: 10,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+        };
+        let delays: Vec<_> = (0..7).map(|attempt| policy.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            [500, 1000, 2000, 4000, 8000, 10_000, 10_000].map(Duration::from_millis)
+        );
+        // Doesn't overflow
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn backoff() {
+        let (res, gaps) = run(
+            vec![
+                Outcome::Unreachable,
+                Outcome::Unreachable,
+                Outcome::Unreachable,
+                Outcome::Unreachable,
+                Outcome::Connected,
+            ],
+            policy(5),
+        );
+        assert_eq!(res, Ok(()));
+        assert_eq!(gaps.len(), 4);
+        for (gap, expected) in gaps.iter().zip([20, 40, 50, 50].map(Duration::from_millis)) {
+            assert!(
+                *gap >= expected,
+                "waited {:?}, expected {:?}",
+                gap,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn max_attempts() {
+        let (res, gaps) = run(
+            vec![
+                Outcome::Unreachable,
+                Outcome::Unreachable,
+                Outcome::Unreachable,
+                Outcome::Connected,
+            ],
+            policy(2),
+        );
+        assert_eq!(
+            res,
+            Err(SocketError::ConnectionFailed("unreachable".into()))
+        );
+        // The first attempt, and two retries
+        assert_eq!(gaps.len(), 2);
+    }
+
+    #[test]
+    fn attempts_reset_after_connecting() {
+        let (res, gaps) = run(
+            vec![
+                Outcome::Unreachable,
+                Outcome::Unreachable,
+                Outcome::Lost,
+                Outcome::Unreachable,
+                Outcome::Connected,
+            ],
+            policy(2),
+        );
+        // Without the reset, we'd give up after losing the connection
+        assert_eq!(res, Ok(()));
+        assert_eq!(gaps.len(), 4);
+    }
+
+    #[test]
+    fn disabled() {
+        let (res, gaps) = run(
+            vec![Outcome::Lost, Outcome::Connected],
+            ReconnectPolicy::disabled(),
+        );
+        assert_eq!(res, Err(SocketError::Signalling("lost".into())));
+        assert!(gaps.is_empty());
+    }
+
+    #[test]
+    fn room_full() {
+        let (res, gaps) = run(vec![Outcome::RoomFull, Outcome::Connected], policy(5));
+        assert_eq!(res, Err(SocketError::RoomFull));
+        assert!(gaps.is_empty());
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let (res, gaps) = run(
+            vec![Outcome::VersionMismatch, Outcome::Connected],
+            policy(5),
+        );
+        assert_eq!(
+            res,
+            Err(SocketError::VersionMismatch {
+                server: PROTOCOL_VERSION + 1
+            })
+        );
+        assert!(gaps.is_empty());
+    }
+
+    #[test]
+    fn closes_when_message_loop_is_gone() {
+        let closed = Arc::new(Mutex::new(false));
+        let signaller = FakeSignaller {
+            outcomes: vec![Outcome::Connected].into(),
+            events: VecDeque::new(),
+            attempts: Default::default(),
+            closed: closed.clone(),
+        };
+        let (requests_sender, requests_receiver) = unbounded();
+        let (events_sender, _events_receiver) = unbounded();
+        drop(requests_sender);
+
+        let res = futures::executor::block_on(reconnecting_signalling_loop(
+            signaller,
+            policy(0),
+            requests_receiver,
+            events_sender,
+        ));
+        assert_eq!(res, Ok(()));
+        assert!(*closed.lock().unwrap());
+    }
+
+    #[test]
+    fn query_param() {
+        assert_eq!(
+            with_query_param("ws://localhost:3536/room", "resume", "abc"),
+            "ws://localhost:3536/room?resume=abc"
+        );
+        assert_eq!(
+            with_query_param("ws://localhost:3536/room?next=2", "resume", "abc"),
+            "ws://localhost:3536/room?next=2&resume=abc"
+        );
+    }
+
+    #[test]
+    fn topology_param() {
+        assert_eq!(
+            with_topology("ws://localhost:3536/room", Topology::Mesh),
+            "ws://localhost:3536/room"
+        );
+        assert_eq!(
+            with_topology("ws://localhost:3536/room?max=4", Topology::Star),
+            "ws://localhost:3536/room?max=4&topology=star"
+        );
+    }
+
+    #[test]
+    fn connection_url_has_version() {
+        assert_eq!(
+            connection_url("ws://localhost:3536/room?next=2", "abc"),
+            format!("ws://localhost:3536/room?next=2&v={PROTOCOL_VERSION}&resume=abc")
+        );
+    }
+
+    #[test]
+    fn resume_tokens_differ() {
+        let token = new_resume_token();
+        assert_eq!(token.len(), 32);
+        assert_ne!(token, new_resume_token());
+    }
+}
@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+/// Statistics about the connection to a peer, see
+/// [`WebRtcSocket::peer_stats`]
+///
+/// Not every platform reports everything, missing values are `None`.
+///
+/// [`WebRtcSocket::peer_stats`]: crate::WebRtcSocket::peer_stats
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Current round trip time of the selected ICE candidate pair
+    ///
+    /// `None` until it's been measured. On native, that's up to webrtc-rs,
+    /// which doesn't measure it as of webrtc 0.6.
+    pub round_trip_time: Option<Duration>,
+    /// Total number of bytes sent to the peer on all data channels
+    pub bytes_sent: u64,
+    /// Total number of bytes received from the peer on all data channels
+    pub bytes_received: u64,
+    /// Total number of packets sent to the peer on all data channels
+    pub packets_sent: u64,
+    /// Total number of packets received from the peer on all data channels
+    pub packets_received: u64,
+    /// Type of the local candidate of the selected ICE candidate pair
+    pub local_candidate_type: Option<CandidateType>,
+    /// Type of the remote candidate of the selected ICE candidate pair
+    pub remote_candidate_type: Option<CandidateType>,
+    /// Number of retransmitted connectivity checks on the selected ICE
+    /// candidate pair
+    ///
+    /// Most browsers don't report this. On native, it's reported along with
+    /// [`PeerStats::round_trip_time`].
+    pub retransmissions: Option<u64>,
+}
+
+/// The type of an ICE candidate, i.e. how a connection is routed
+///
+/// See also: <https://developer.mozilla.org/en-US/docs/Web/API/RTCIceCandidate/type>
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CandidateType {
+    /// A direct connection, `host`
+    Host,
+    /// An address discovered through a STUN server, `srflx`
+    ServerReflexive,
+    /// An address discovered during connectivity checks, `prflx`
+    PeerReflexive,
+    /// Relayed through a TURN server, `relay`
+    Relay,
+}
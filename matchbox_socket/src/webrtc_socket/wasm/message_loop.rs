@@ -1,4 +1,7 @@
-use futures::FutureExt;
+use futures::{
+    future::{Fuse, FusedFuture, LocalBoxFuture},
+    FutureExt,
+};
 use futures::{stream::FuturesUnordered, StreamExt};
 use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
 use futures_timer::Delay;
@@ -12,58 +15,95 @@ use wasm_bindgen::{prelude::*, JsCast, JsValue};
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
     MessageEvent, RtcConfiguration, RtcDataChannel, RtcDataChannelInit, RtcDataChannelType,
-    RtcIceCandidate, RtcIceCandidateInit, RtcPeerConnection, RtcSdpType, RtcSessionDescriptionInit,
+    RtcIceCandidate, RtcIceCandidateInit, RtcIceConnectionState, RtcOfferOptions,
+    RtcPeerConnection, RtcSdpType, RtcSessionDescriptionInit, RtcSignalingState,
 };
 
 use crate::webrtc_socket::{
-    messages::{PeerEvent, PeerId, PeerRequest, PeerSignal},
+    error::HandshakeTimedOut,
+    is_polite,
+    messages::{PeerEvent, PeerId, PeerRequest, PeerSignal, KEEP_ALIVE_INTERVAL},
     signal_peer::SignalPeer,
-    Packet, WebRtcSocketConfig, DATA_CHANNEL_ID, KEEP_ALIVE_INTERVAL,
+    CandidateType, ChannelConfig, Packet, PeerState, PeerStats, Recipients, SocketError,
+    WebRtcSocketConfig, DATA_CHANNEL_ID, STATS_INTERVAL,
 };
 
+#[allow(clippy::too_many_arguments)]
 pub async fn message_loop(
-    id: PeerId,
+    id_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
+    host_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
     config: WebRtcSocketConfig,
     requests_sender: futures_channel::mpsc::UnboundedSender<PeerRequest>,
     mut events_receiver: futures_channel::mpsc::UnboundedReceiver<PeerEvent>,
-    mut peer_messages_out_rx: futures_channel::mpsc::UnboundedReceiver<(PeerId, Packet)>,
-    new_connected_peers_tx: futures_channel::mpsc::UnboundedSender<PeerId>,
-    messages_from_peers_tx: futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>,
-) {
+    mut peer_messages_out_rx: futures_channel::mpsc::UnboundedReceiver<(Recipients, usize, Packet)>,
+    peer_state_tx: futures_channel::mpsc::UnboundedSender<(PeerId, PeerState)>,
+    peer_stats_tx: futures_channel::mpsc::UnboundedSender<(PeerId, PeerStats)>,
+    messages_from_peers_tx: Vec<futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>>,
+) -> Result<(), SocketError> {
     debug!("Entering WebRtcSocket message loop");
 
-    requests_sender
-        .unbounded_send(PeerRequest::Uuid(id))
-        .expect("failed to send uuid");
-
-    let mut offer_handshakes = FuturesUnordered::new();
-    let mut accept_handshakes = FuturesUnordered::new();
+    let mut handshakes: FuturesUnordered<LocalBoxFuture<HandshakeResult>> = FuturesUnordered::new();
+    let mut peer_loops = FuturesUnordered::new();
     let mut handshake_signals = HashMap::new();
-    let mut data_channels: HashMap<PeerId, RtcDataChannel> = HashMap::new();
+    let mut data_channels: HashMap<PeerId, Vec<RtcDataChannel>> = HashMap::new();
+    let mut connections: HashMap<PeerId, RtcPeerConnection> = HashMap::new();
+    let mut stats_requests = FuturesUnordered::new();
+    let mut own_id = None;
+    let (peer_disconnected_tx, mut peer_disconnected_rx) = futures_channel::mpsc::unbounded();
 
-    let mut timeout = Delay::new(Duration::from_millis(KEEP_ALIVE_INTERVAL)).fuse();
+    let mut timeout = Delay::new(KEEP_ALIVE_INTERVAL).fuse();
+    let mut stats_timeout = Delay::new(Duration::from_millis(STATS_INTERVAL)).fuse();
 
     loop {
         select! {
             _ = &mut timeout => {
-                requests_sender.unbounded_send(PeerRequest::KeepAlive).expect("send failed");
-                timeout = Delay::new(Duration::from_millis(KEEP_ALIVE_INTERVAL)).fuse();
+                // Nothing to keep alive if the signalling loop is gone
+                let _ = requests_sender.unbounded_send(PeerRequest::KeepAlive);
+                timeout = Delay::new(KEEP_ALIVE_INTERVAL).fuse();
+            }
+
+            _ = &mut stats_timeout => {
+                for (peer, connection) in &connections {
+                    let peer = peer.clone();
+                    stats_requests.push(peer_stats(connection.clone()).map(move |stats| (peer, stats)));
+                }
+                stats_timeout = Delay::new(Duration::from_millis(STATS_INTERVAL)).fuse();
+            }
+
+            message = stats_requests.select_next_some() => {
+                let (peer, stats) = message;
+                match stats {
+                    // The socket may have been dropped, which we'll notice below
+                    Ok(stats) => { let _ = peer_stats_tx.unbounded_send((peer, stats)); }
+                    Err(err) => warn!("failed to get stats for peer {peer}: {err:?}"),
+                }
             }
 
-            res = offer_handshakes.select_next_some() => {
-                check(&res);
-                let peer = res.unwrap();
-                data_channels.insert(peer.0.clone(), peer.1.clone());
-                debug!("Notifying about new peer");
-                new_connected_peers_tx.unbounded_send(peer.0).expect("send failed");
+            res = handshakes.select_next_some() => {
+                if let Some(handshake) = check_handshake_result(res, &mut handshake_signals, &peer_state_tx) {
+                    let peer = handshake.peer_id.clone();
+                    connections.insert(peer.clone(), handshake.conn.clone());
+                    data_channels.insert(peer.clone(), handshake.data_channels.clone());
+                    let signal_peer = SignalPeer::new(peer.clone(), requests_sender.clone());
+                    let polite = matches!(&own_id, Some(id) if is_polite(id, &peer));
+                    peer_loops.push(peer_loop(signal_peer, polite, handshake, peer_disconnected_tx.clone(), &config));
+                    debug!("Notifying about new peer");
+                    // The socket may have been dropped, which we'll notice below
+                    let _ = peer_state_tx.unbounded_send((peer, PeerState::Connected));
+                }
             },
-            res = accept_handshakes.select_next_some() => {
-                // TODO: this could be de-duplicated
-                check(&res);
-                let peer = res.unwrap();
-                data_channels.insert(peer.0.clone(), peer.1.clone());
-                debug!("Notifying about new peer");
-                new_connected_peers_tx.unbounded_send(peer.0).expect("send failed");
+
+            _ = peer_loops.select_next_some() => {},
+
+            peer = peer_disconnected_rx.select_next_some() => {
+                // Both the data channel and the peer connection may report a
+                // disconnect, only forward the first one.
+                handshake_signals.remove(&peer);
+                connections.remove(&peer);
+                if data_channels.remove(&peer).is_some() {
+                    debug!("Notifying about disconnected peer");
+                    let _ = peer_state_tx.unbounded_send((peer, PeerState::Disconnected));
+                }
             },
 
             message = events_receiver.next() => {
@@ -71,50 +111,79 @@ pub async fn message_loop(
                     debug!("{:?}", event);
 
                     match event {
+                        PeerEvent::IdAssigned(id) => {
+                            debug!("I am {id}");
+                            own_id = Some(id.clone());
+                            // The socket may have been dropped, which we'll notice below
+                            let _ = id_tx.unbounded_send(id);
+                        }
+                        PeerEvent::HostAssigned(host) => {
+                            debug!("{host} is the host");
+                            // The socket may have been dropped, which we'll notice below
+                            let _ = host_tx.unbounded_send(host);
+                        }
+                        PeerEvent::NewPeer(peer_uuid) if handshake_signals.contains_key(&peer_uuid) || data_channels.contains_key(&peer_uuid) => {
+                            // We're already connected to this peer, keep the existing connection
+                            debug!("ignoring new peer event for known peer {peer_uuid}");
+                        }
                         PeerEvent::NewPeer(peer_uuid) => {
                             let (signal_sender, signal_receiver) = futures_channel::mpsc::unbounded();
                             handshake_signals.insert(peer_uuid.clone(), signal_sender);
-                            let signal_peer = SignalPeer::new(peer_uuid, requests_sender.clone());
-                            offer_handshakes.push(handshake_offer(signal_peer, signal_receiver, messages_from_peers_tx.clone(), &config));
+                            let signal_peer = SignalPeer::new(peer_uuid.clone(), requests_sender.clone());
+                            let polite = matches!(&own_id, Some(id) if is_polite(id, &peer_uuid));
+                            let handshake_fut = handshake_offer(signal_peer, signal_receiver, polite, peer_disconnected_tx.clone(), messages_from_peers_tx.clone(), &config);
+                            handshakes.push(handshake_fut.map(move |res| res.map_err(|e| (peer_uuid, e))).boxed_local());
+                        }
+                        // Handled by the signalling loop
+                        PeerEvent::Error(_) => {}
+                        PeerEvent::PeerLeft(peer_uuid) => {
+                            // Dropping the signal queue aborts the handshake, if it's still going
+                            handshake_signals.remove(&peer_uuid);
+                            if let Some(connection) = connections.remove(&peer_uuid) {
+                                connection.close();
+                            }
+                            if data_channels.remove(&peer_uuid).is_some() {
+                                debug!("peer {peer_uuid} left");
+                                let _ = peer_state_tx.unbounded_send((peer_uuid, PeerState::Disconnected));
+                            }
                         }
                         PeerEvent::Signal { sender, data } => {
                             let from_peer_sender = handshake_signals.entry(sender.clone()).or_insert_with(|| {
                                 let (from_peer_sender, from_peer_receiver) = futures_channel::mpsc::unbounded();
                                 let signal_peer = SignalPeer::new(sender.clone(), requests_sender.clone());
                                 // We didn't start signalling with this peer, assume we're the accepting part
-                                accept_handshakes.push(handshake_accept(signal_peer, from_peer_receiver, messages_from_peers_tx.clone(), &config));
+                                let handshake_fut = handshake_accept(signal_peer, from_peer_receiver, None, peer_disconnected_tx.clone(), messages_from_peers_tx.clone(), &config);
+                                let peer = sender.clone();
+                                handshakes.push(handshake_fut.map(move |res| res.map_err(|e| (peer, e))).boxed_local());
                                 from_peer_sender
                             });
                             if let Err(e) = from_peer_sender.unbounded_send(data) {
-                                if e.is_disconnected() && data_channels.contains_key(&sender) {
-                                    // when the handshake finishes, it currently drops the receiver.
-                                    // ideally, we should keep this channel open and process additional ice candidates,
-                                    // but currently we don't.
-
-                                    // If this happens, however, it means that the handshake is already is done,
-                                    // so it should probably be nothing to worry about
-                                    warn!("ignoring signal from peer after handshake completed: {e:?}");
-                                } else {
-                                    error!("failed to forward signal to handshaker: {e:?}");
-                                }
+                                warn!("failed to forward signal from peer {sender}: {e:?}");
                             }
                         }
                     }
                 } else {
-                    error!("Disconnected from signalling server!");
-                    break;
+                    // Connections to peers we already have keep working, we
+                    // just won't hear about new ones
+                    warn!("Disconnected from signalling server");
                 }
             }
 
             message = peer_messages_out_rx.next() => {
                 match message {
-                    Some(message) => {
-                        let data_channel = data_channels.get(&message.0).expect("couldn't find data channel for peer");
-                        if let Err(err) = data_channel.send_with_u8_array(&message.1) {
-                            // This likely means the other peer disconnected
-                            // todo: we should probably remove the data channel object in this case
-                            // and try reconnecting. For now we will just stop panicking.
-                            error!("Failed to send: {err:?}");
+                    Some((recipients, channel, packet)) => {
+                        for peer in recipients.iter() {
+                            match data_channels.get(peer) {
+                                Some(data_channels) => {
+                                    if let Err(err) = data_channels[channel].send_with_u8_array(&packet) {
+                                        // This likely means the other peer disconnected, which
+                                        // will be reported when the data channel closes.
+                                        error!("Failed to send: {err:?}");
+                                    }
+                                }
+                                // The peer most likely disconnected
+                                None => warn!("couldn't find data channel for peer {peer}, dropping packet"),
+                            }
                         }
                     },
                     None => {
@@ -132,260 +201,552 @@ pub async fn message_loop(
         }
     }
     debug!("Message loop finished");
+    Ok(())
+}
+
+/// The connection to a peer, once the handshake is done
+struct Handshake {
+    peer_id: PeerId,
+    conn: RtcPeerConnection,
+    data_channels: Vec<RtcDataChannel>,
+    /// Signals the peer keeps sending, i.e. ICE candidates and ICE restarts
+    signal_receiver: UnboundedReceiver<PeerSignal>,
+    ice_state_rx: UnboundedReceiver<RtcIceConnectionState>,
+    /// Keeps sending our ICE candidates to the peer
+    onicecandidate: Option<Closure<dyn FnMut(JsValue)>>,
 }
 
+type HandshakeResult = Result<Handshake, (PeerId, Box<dyn std::error::Error>)>;
+
+/// Handshakes with peers that have left or disconnected are aborted, which
+/// isn't an error, and handshakes that time out or fail otherwise, e.g.
+/// because the peer sent an offer we can't use, are reported as failed
+/// connections
+fn check_handshake_result(
+    res: HandshakeResult,
+    handshake_signals: &mut HashMap<PeerId, UnboundedSender<PeerSignal>>,
+    peer_state_tx: &UnboundedSender<(PeerId, PeerState)>,
+) -> Option<Handshake> {
+    let peer = match res {
+        Ok(handshake) => return Some(handshake),
+        Err((peer, reason)) if !handshake_signals.contains_key(&peer) => {
+            debug!("abandoned handshake with peer {peer}: {reason}");
+            return None;
+        }
+        Err((peer, reason)) if reason.is::<HandshakeTimedOut>() => {
+            debug!("giving up on connecting to peer {peer}");
+            peer
+        }
+        Err((peer, reason)) => {
+            warn!("handshake with peer {peer} failed: {reason}");
+            peer
+        }
+    };
+    handshake_signals.remove(&peer);
+    // The socket may have been dropped, which we'll notice later
+    let _ = peer_state_tx.unbounded_send((peer, PeerState::ConnectionFailed));
+    None
+}
+
+/// Closes the connection of a handshake that can't complete
+fn abort_handshake(conn: &RtcPeerConnection) -> Box<dyn std::error::Error> {
+    conn.close();
+    "signalling with peer ended in the middle of the handshake".into()
+}
+
+/// Sends an offer to the peer, or answers the peer's own offer instead if
+/// both of us offered at once and we're the polite side
+///
+/// Attempts that time out are retried with a fresh connection and offer.
 async fn handshake_offer(
     signal_peer: SignalPeer,
     mut signal_receiver: UnboundedReceiver<PeerSignal>,
-    messages_from_peers_tx: UnboundedSender<(PeerId, Packet)>,
+    polite: bool,
+    peer_disconnected_tx: UnboundedSender<PeerId>,
+    messages_from_peers_tx: Vec<UnboundedSender<(PeerId, Packet)>>,
     config: &WebRtcSocketConfig,
-) -> Result<(PeerId, RtcDataChannel), Box<dyn std::error::Error>> {
-    debug!("making offer");
-
-    let conn = create_rtc_peer_connection(config);
-    let (channel_ready_tx, mut channel_ready_rx) = futures_channel::mpsc::channel(1);
-    let data_channel = create_data_channel(
-        conn.clone(),
-        messages_from_peers_tx,
-        signal_peer.id.clone(),
-        channel_ready_tx,
-    );
+) -> Result<Handshake, Box<dyn std::error::Error>> {
+    for attempt in 0..=config.handshake.max_retries {
+        if attempt > 0 {
+            debug!("handshake with {} timed out, retrying", signal_peer.id);
+        }
+        debug!("making offer");
+        let mut pending = PendingConnection::new(
+            config,
+            &signal_peer,
+            &peer_disconnected_tx,
+            messages_from_peers_tx.clone(),
+        )?;
+        let conn = pending.conn.clone();
+        send_offer(&conn, &signal_peer, &RtcOfferOptions::new()).await?;
+        debug!("created offer for new peer");
+
+        let mut timeout = Delay::new(config.handshake.timeout).fuse();
+        loop {
+            match pending.next_event(&mut signal_receiver, &mut timeout).await {
+                HandshakeEvent::Answer(answer) => {
+                    debug!("setting remote description");
+                    if let Err(e) = pending
+                        .set_remote_description(&signal_peer, RtcSdpType::Answer, &answer)
+                        .await
+                    {
+                        // Most likely a late answer to an earlier attempt
+                        warn!("failed to set answer as remote description: {e:?}");
+                    }
+                }
+                HandshakeEvent::Offer(offer) if polite => {
+                    debug!("offer collision with {}, answering theirs", signal_peer.id);
+                    let rollback = RtcSessionDescriptionInit::new(RtcSdpType::Rollback);
+                    JsFuture::from(conn.set_local_description(&rollback))
+                        .await
+                        .efix()?;
+                    pending.accept_offer(&signal_peer, offer).await?;
+                    return handshake_accept(
+                        signal_peer,
+                        signal_receiver,
+                        Some(pending),
+                        peer_disconnected_tx,
+                        messages_from_peers_tx,
+                        config,
+                    )
+                    .await;
+                }
+                HandshakeEvent::Offer(_) => {
+                    // The polite peer answers our offer instead
+                    debug!("offer collision with {}, ignoring theirs", signal_peer.id);
+                }
+                HandshakeEvent::ChannelsReady => {
+                    return Ok(pending.connected(signal_peer.id, signal_receiver))
+                }
+                HandshakeEvent::TimedOut | HandshakeEvent::IceFailed => {
+                    pending.discard();
+                    break;
+                }
+                HandshakeEvent::PeerLeft => return Err(abort_handshake(&conn)),
+            }
+        }
+    }
+    Err(Box::new(HandshakeTimedOut))
+}
 
-    // Create offer
-    let offer = JsFuture::from(conn.create_offer()).await.efix()?;
-    let offer_sdp = Reflect::get(&offer, &JsValue::from_str("sdp"))
-        .efix()?
-        .as_string()
-        .ok_or("")?;
-    let mut rtc_session_desc_init_dict = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
-    let offer_description = rtc_session_desc_init_dict.sdp(&offer_sdp);
-    JsFuture::from(conn.set_local_description(offer_description))
-        .await
-        .efix()?;
-    debug!("created offer for new peer");
-    signal_peer.send(PeerSignal::Offer(conn.local_description().unwrap().sdp()));
-
-    let mut received_candidates = vec![];
-
-    // Wait for answer
-    let sdp = loop {
-        let signal = signal_receiver
-            .next()
-            .await
-            .ok_or("Signal server connection lost in the middle of a handshake")?;
-
-        match signal {
-            PeerSignal::Answer(answer) => break answer,
-            PeerSignal::IceCandidate(candidate) => {
-                debug!("got an IceCandidate signal! {}", candidate);
-                received_candidates.push(candidate);
+/// Answers the peer's offer
+///
+/// `answered` is a connection that already answered it, after an offer
+/// collision. If the peer times out and offers again, the answer is made
+/// from a fresh connection.
+async fn handshake_accept(
+    signal_peer: SignalPeer,
+    mut signal_receiver: UnboundedReceiver<PeerSignal>,
+    answered: Option<PendingConnection>,
+    peer_disconnected_tx: UnboundedSender<PeerId>,
+    messages_from_peers_tx: Vec<UnboundedSender<(PeerId, Packet)>>,
+    config: &WebRtcSocketConfig,
+) -> Result<Handshake, Box<dyn std::error::Error>> {
+    debug!("handshake_accept");
+    // Leave time for all the attempts of the offering side
+    let mut timeout =
+        Delay::new(config.handshake.timeout * (config.handshake.max_retries + 1)).fuse();
+    let (mut pending, mut accepted) = match answered {
+        Some(pending) => (pending, true),
+        None => {
+            let pending = PendingConnection::new(
+                config,
+                &signal_peer,
+                &peer_disconnected_tx,
+                messages_from_peers_tx.clone(),
+            )?;
+            (pending, false)
+        }
+    };
+
+    loop {
+        match pending.next_event(&mut signal_receiver, &mut timeout).await {
+            HandshakeEvent::Offer(offer) => {
+                if accepted {
+                    debug!("{} made a new offer, starting over", signal_peer.id);
+                    pending.discard();
+                    pending = PendingConnection::new(
+                        config,
+                        &signal_peer,
+                        &peer_disconnected_tx,
+                        messages_from_peers_tx.clone(),
+                    )?;
+                }
+                debug!("received offer");
+                pending.accept_offer(&signal_peer, offer).await?;
+                accepted = true;
             }
-            _ => {
-                warn!("ignoring unexpected signal: {signal:?}");
+            HandshakeEvent::Answer(answer) => {
+                // We only accept when the peer offered first, so there's no
+                // offer of ours to answer
+                warn!("ignoring unexpected answer: {answer:?}");
             }
-        };
-    };
+            HandshakeEvent::ChannelsReady => {
+                return Ok(pending.connected(signal_peer.id, signal_receiver))
+            }
+            HandshakeEvent::TimedOut => {
+                pending.discard();
+                return Err(Box::new(HandshakeTimedOut));
+            }
+            HandshakeEvent::IceFailed => {
+                // The offering side notices too, and makes a new offer
+                debug!(
+                    "connection to {} failed, waiting for a new offer",
+                    signal_peer.id
+                );
+            }
+            HandshakeEvent::PeerLeft => return Err(abort_handshake(&pending.conn)),
+        }
+    }
+}
 
-    // Set remote description
-    let mut remote_description = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
-    remote_description.sdp(&sdp);
-    debug!("setting remote description");
-    JsFuture::from(conn.set_remote_description(&remote_description))
-        .await
-        .efix()?;
+/// What interrupted waiting for a handshake to complete
+enum HandshakeEvent {
+    /// The peer sent an offer
+    Offer(String),
+    /// The peer answered our offer
+    Answer(String),
+    /// All data channels are open
+    ChannelsReady,
+    /// The handshake took too long
+    TimedOut,
+    /// No way was found to reach the peer
+    IceFailed,
+    /// Signalling with the peer ended, i.e. the peer left
+    PeerLeft,
+}
+
+/// A peer connection and its data channels, while handshaking
+struct PendingConnection {
+    conn: RtcPeerConnection,
+    data_channels: Vec<RtcDataChannel>,
+    channel_ready_rx: futures_channel::mpsc::Receiver<u8>,
+    channels_ready: usize,
+    ice_state_rx: UnboundedReceiver<RtcIceConnectionState>,
+    /// Remote ICE candidates that arrived before the remote description
+    received_candidates: Vec<String>,
+    /// Sends our ICE candidates to the peer, once it has our description
+    onicecandidate: Option<Closure<dyn FnMut(JsValue)>>,
+}
+
+impl PendingConnection {
+    fn new(
+        config: &WebRtcSocketConfig,
+        signal_peer: &SignalPeer,
+        peer_disconnected_tx: &UnboundedSender<PeerId>,
+        messages_from_peers_tx: Vec<UnboundedSender<(PeerId, Packet)>>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let (conn, ice_state_rx) = create_rtc_peer_connection(config)?;
+        let (channel_ready_tx, channel_ready_rx) =
+            futures_channel::mpsc::channel(config.channels.len());
+        let data_channels = create_data_channels(
+            conn.clone(),
+            messages_from_peers_tx,
+            signal_peer.id.clone(),
+            channel_ready_tx,
+            peer_disconnected_tx.clone(),
+            &config.channels,
+        );
+        Ok(Self {
+            conn,
+            data_channels,
+            channel_ready_rx,
+            channels_ready: 0,
+            ice_state_rx,
+            received_candidates: vec![],
+            onicecandidate: None,
+        })
+    }
 
-    // send ICE candidates to remote peer
-    let signal_peer_ice = signal_peer.clone();
-    let onicecandidate: Box<dyn FnMut(JsValue)> = Box::new(move |event| {
-        let event = Reflect::get(&event, &JsValue::from_str("candidate")).efix();
-        if let Ok(event) = event {
-            if let Ok(candidate) = event.dyn_into::<RtcIceCandidate>() {
-                debug!("sending IceCandidate signal {}", candidate.candidate());
-                signal_peer_ice.send(PeerSignal::IceCandidate(candidate.candidate()));
+    /// Adds remote ICE candidates until something else happens
+    async fn next_event(
+        &mut self,
+        signal_receiver: &mut UnboundedReceiver<PeerSignal>,
+        mut timeout: &mut Fuse<Delay>,
+    ) -> HandshakeEvent {
+        while self.channels_ready < self.data_channels.len() {
+            select! {
+                _ = self.channel_ready_rx.next() => {
+                    debug!("channel ready");
+                    self.channels_ready += 1;
+                }
+                signal = signal_receiver.next() => match signal {
+                    Some(PeerSignal::IceCandidate(candidate)) => {
+                        debug!("got an IceCandidate signal! {}", candidate);
+                        if self.conn.remote_description().is_some() {
+                            if let Err(e) = add_ice_candidate(&self.conn, &candidate).await {
+                                warn!("failed to add remote ice candidate: {e:?}");
+                            }
+                        } else {
+                            self.received_candidates.push(candidate);
+                        }
+                    }
+                    Some(PeerSignal::Offer(offer)) => return HandshakeEvent::Offer(offer),
+                    Some(PeerSignal::Answer(answer)) => return HandshakeEvent::Answer(answer),
+                    None => return HandshakeEvent::PeerLeft,
+                },
+                state = self.ice_state_rx.select_next_some() => {
+                    if state == RtcIceConnectionState::Failed {
+                        return HandshakeEvent::IceFailed;
+                    }
+                }
+                _ = timeout => return HandshakeEvent::TimedOut,
             }
         }
-    });
-    let onicecandidate = Closure::wrap(onicecandidate);
-    conn.set_onicecandidate(Some(onicecandidate.as_ref().unchecked_ref()));
-
-    // handle pending ICE candidates
-    for candidate in received_candidates {
-        let mut ice_candidate = RtcIceCandidateInit::new(&candidate);
-        ice_candidate.sdp_m_line_index(Some(0));
-        JsFuture::from(
-            conn.add_ice_candidate_with_opt_rtc_ice_candidate_init(Some(&ice_candidate)),
-        )
-        .await
-        .efix()?;
+        HandshakeEvent::ChannelsReady
     }
 
-    // select for channel ready or ice candidates
-    debug!("waiting for data channel to open");
-    loop {
-        select! {
-            _ = channel_ready_rx.next() => {
-                debug!("channel ready");
-                break;
-            }
-            msg = signal_receiver.next() => {
-                if let Some(PeerSignal::IceCandidate(candidate)) = msg {
-                    debug!("got an IceCandidate signal! {}", candidate);
-                    let mut ice_candidate = RtcIceCandidateInit::new(&candidate);
-                    ice_candidate.sdp_m_line_index(Some(0));
-                    JsFuture::from(
-                        conn.add_ice_candidate_with_opt_rtc_ice_candidate_init(Some(&ice_candidate)),
-                    )
-                    .await
-                    .efix()?;
+    /// Sets the remote description, after which ICE candidates can be
+    /// exchanged
+    async fn set_remote_description(
+        &mut self,
+        signal_peer: &SignalPeer,
+        sdp_type: RtcSdpType,
+        sdp: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        set_remote_description(&self.conn, sdp_type, sdp).await?;
+
+        // send ICE candidates to remote peer
+        let signal_peer_ice = signal_peer.clone();
+        let onicecandidate: Box<dyn FnMut(JsValue)> = Box::new(move |event| {
+            let event = Reflect::get(&event, &JsValue::from_str("candidate")).efix();
+            if let Ok(event) = event {
+                if let Ok(candidate) = event.dyn_into::<RtcIceCandidate>() {
+                    debug!("sending IceCandidate signal {}", candidate.candidate());
+                    signal_peer_ice.send(PeerSignal::IceCandidate(candidate.candidate()));
                 }
             }
-        };
+        });
+        let onicecandidate = Closure::wrap(onicecandidate);
+        self.conn
+            .set_onicecandidate(Some(onicecandidate.as_ref().unchecked_ref()));
+        self.onicecandidate = Some(onicecandidate);
+
+        // handle pending ICE candidates
+        for candidate in std::mem::take(&mut self.received_candidates) {
+            if let Err(e) = add_ice_candidate(&self.conn, &candidate).await {
+                warn!("failed to add remote ice candidate: {e:?}");
+            }
+        }
+        Ok(())
     }
 
-    // stop listening for ICE candidates
-    // TODO: we should support getting new ICE candidates even after connecting,
-    //       since it's possible to return to the ice gathering state
-    // See: <https://developer.mozilla.org/en-US/docs/Web/API/RTCPeerConnection/iceGatheringState>
-    conn.set_onicecandidate(None);
+    /// Sets the peer's offer as the remote description, and sends it our answer
+    async fn accept_offer(
+        &mut self,
+        signal_peer: &SignalPeer,
+        offer: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.set_remote_description(signal_peer, RtcSdpType::Offer, &offer)
+            .await?;
+        debug!("set remote_description from offer");
+        send_answer(&self.conn, signal_peer).await
+    }
 
-    debug!("Ice completed: {:?}", conn.ice_gathering_state());
+    /// Keeps exchanging ICE candidates with the peer, since gathering may
+    /// still be going on, or start over when ICE restarts
+    fn connected(
+        self,
+        peer_id: PeerId,
+        signal_receiver: UnboundedReceiver<PeerSignal>,
+    ) -> Handshake {
+        debug!("Ice gathering state: {:?}", self.conn.ice_gathering_state());
+        Handshake {
+            peer_id,
+            conn: self.conn,
+            data_channels: self.data_channels,
+            signal_receiver,
+            ice_state_rx: self.ice_state_rx,
+            onicecandidate: self.onicecandidate,
+        }
+    }
 
-    Ok((signal_peer.id, data_channel))
+    /// Closes a connection that's being replaced, without reporting the peer
+    /// as disconnected
+    fn discard(self) {
+        self.conn.set_onicecandidate(None);
+        self.conn.set_oniceconnectionstatechange(None);
+        for channel in &self.data_channels {
+            channel.set_onclose(None);
+        }
+        self.conn.close();
+    }
 }
 
-async fn handshake_accept(
+/// Handles the signals a connected peer keeps sending, and restarts ICE when
+/// the connection fails, until the peer is gone
+///
+/// The impolite peer sends the restart offer, so they don't collide.
+async fn peer_loop(
     signal_peer: SignalPeer,
-    mut signal_receiver: UnboundedReceiver<PeerSignal>,
-    messages_from_peers_tx: UnboundedSender<(PeerId, Packet)>,
+    polite: bool,
+    handshake: Handshake,
+    peer_disconnected_tx: UnboundedSender<PeerId>,
     config: &WebRtcSocketConfig,
-) -> Result<(PeerId, RtcDataChannel), Box<dyn std::error::Error>> {
-    debug!("handshake_accept");
-
-    let conn = create_rtc_peer_connection(config);
-    let (channel_ready_tx, mut channel_ready_rx) = futures_channel::mpsc::channel(1);
-    let data_channel = create_data_channel(
-        conn.clone(),
-        messages_from_peers_tx,
-        signal_peer.id.clone(),
-        channel_ready_tx,
-    );
+) {
+    let Handshake {
+        conn,
+        mut signal_receiver,
+        mut ice_state_rx,
+        onicecandidate,
+        ..
+    } = handshake;
+    // Running while an ICE restart is in progress
+    let mut restart_timeout = Fuse::terminated();
 
-    let mut received_candidates = vec![];
+    loop {
+        select! {
+            signal = signal_receiver.next() => match signal {
+                Some(signal) => handle_signal(&conn, &signal_peer, signal).await,
+                // The peer left or disconnected
+                None => break,
+            },
+            state = ice_state_rx.select_next_some() => match state {
+                RtcIceConnectionState::Disconnected | RtcIceConnectionState::Failed
+                    if restart_timeout.is_terminated() =>
+                {
+                    debug!("connection to {} is {state:?}, restarting ice", signal_peer.id);
+                    restart_timeout = Delay::new(config.handshake.timeout).fuse();
+                    if !polite {
+                        let mut options = RtcOfferOptions::new();
+                        options.ice_restart(true);
+                        if let Err(e) = send_offer(&conn, &signal_peer, &options).await {
+                            warn!("failed to restart ice: {e:?}");
+                        }
+                    }
+                }
+                RtcIceConnectionState::Connected | RtcIceConnectionState::Completed => {
+                    restart_timeout = Fuse::terminated()
+                }
+                _ => {}
+            },
+            _ = restart_timeout => {
+                warn!("failed to restart ice with {}, disconnecting", signal_peer.id);
+                // The message loop may already be gone if the socket was dropped
+                let _ = peer_disconnected_tx.unbounded_send(signal_peer.id.clone());
+                break;
+            }
+        }
+    }
 
-    let offer = loop {
-        let signal = signal_receiver
-            .next()
-            .await
-            .ok_or("Signal server connection lost in the middle of a handshake")?;
+    // The closure can't be called once it's dropped
+    conn.set_onicecandidate(None);
+    drop(onicecandidate);
+}
 
-        match signal {
-            PeerSignal::Offer(o) => {
-                break o;
-            }
-            PeerSignal::IceCandidate(candidate) => {
-                debug!("got an IceCandidate signal! {}", candidate);
-                received_candidates.push(candidate);
-            }
-            _ => {
-                warn!("ignoring unexpected signal: {signal:?}");
+/// Handles a signal from a peer after the handshake
+async fn handle_signal(conn: &RtcPeerConnection, signal_peer: &SignalPeer, signal: PeerSignal) {
+    let res = match signal {
+        PeerSignal::IceCandidate(candidate) => {
+            debug!("got an IceCandidate signal! {}", candidate);
+            add_ice_candidate(conn, &candidate).await
+        }
+        PeerSignal::Offer(_) if conn.signaling_state() == RtcSignalingState::HaveLocalOffer => {
+            // Only the impolite peer restarts ICE, so this is a late offer
+            // from the handshake
+            debug!("ignoring offer colliding with our ice restart");
+            Ok(())
+        }
+        PeerSignal::Offer(offer) => {
+            debug!("{} is restarting ice", signal_peer.id);
+            match set_remote_description(conn, RtcSdpType::Offer, &offer).await {
+                Ok(()) => send_answer(conn, signal_peer).await,
+                Err(e) => Err(e),
             }
         }
+        PeerSignal::Answer(answer) => {
+            set_remote_description(conn, RtcSdpType::Answer, &answer).await
+        }
     };
-    debug!("received offer");
-
-    // Set remote description
-    {
-        let mut remote_description = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
-        let sdp = offer;
-        remote_description.sdp(&sdp);
-        JsFuture::from(conn.set_remote_description(&remote_description))
-            .await
-            .expect("failed to set remote description");
-        debug!("set remote_description from offer");
+    if let Err(e) = res {
+        warn!("failed to handle signal from {}: {e:?}", signal_peer.id);
     }
+}
 
-    let answer = JsFuture::from(conn.create_answer())
+/// Sets our offer as the local description, and sends it to the peer
+async fn send_offer(
+    conn: &RtcPeerConnection,
+    signal_peer: &SignalPeer,
+    options: &RtcOfferOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let offer = JsFuture::from(conn.create_offer_with_rtc_offer_options(options))
         .await
-        .expect("error creating answer");
+        .efix()?;
+    let offer_sdp = Reflect::get(&offer, &JsValue::from_str("sdp"))
+        .efix()?
+        .as_string()
+        .ok_or("")?;
+    let mut rtc_session_desc_init_dict = RtcSessionDescriptionInit::new(RtcSdpType::Offer);
+    let offer_description = rtc_session_desc_init_dict.sdp(&offer_sdp);
+    JsFuture::from(conn.set_local_description(offer_description))
+        .await
+        .efix()?;
+    signal_peer.send(PeerSignal::Offer(
+        conn.local_description()
+            .ok_or("no local description")?
+            .sdp(),
+    ));
+    Ok(())
+}
 
+/// Sets our answer to the peer's offer as the local description, and sends
+/// it to the peer
+async fn send_answer(
+    conn: &RtcPeerConnection,
+    signal_peer: &SignalPeer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let answer = JsFuture::from(conn.create_answer()).await.efix()?;
     debug!("created answer");
 
-    let mut session_desc_init = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
-
     let answer_sdp = Reflect::get(&answer, &JsValue::from_str("sdp"))
         .efix()?
         .as_string()
         .ok_or("")?;
-
+    let mut session_desc_init = RtcSessionDescriptionInit::new(RtcSdpType::Answer);
     let answer_description = session_desc_init.sdp(&answer_sdp);
-
     JsFuture::from(conn.set_local_description(answer_description))
         .await
         .efix()?;
 
-    let answer = PeerSignal::Answer(conn.local_description().unwrap().sdp());
-    signal_peer.send(answer);
-
-    // send ICE candidates to remote peer
-    let signal_peer_ice = signal_peer.clone();
-    let onicecandidate: Box<dyn FnMut(JsValue)> = Box::new(move |event| {
-        let event = Reflect::get(&event, &JsValue::from_str("candidate")).efix();
-        if let Ok(event) = event {
-            if let Ok(candidate) = event.dyn_into::<RtcIceCandidate>() {
-                debug!("sending IceCandidate signal {}", candidate.candidate());
-                signal_peer_ice.send(PeerSignal::IceCandidate(candidate.candidate()));
-            }
-        }
-    });
-    let onicecandidate = Closure::wrap(onicecandidate);
-    conn.set_onicecandidate(Some(onicecandidate.as_ref().unchecked_ref()));
-
-    // handle pending ICE candidates
-    for candidate in received_candidates {
-        let mut ice_candidate = RtcIceCandidateInit::new(&candidate);
-        ice_candidate.sdp_m_line_index(Some(0));
-        JsFuture::from(
-            conn.add_ice_candidate_with_opt_rtc_ice_candidate_init(Some(&ice_candidate)),
-        )
+    signal_peer.send(PeerSignal::Answer(
+        conn.local_description()
+            .ok_or("no local description")?
+            .sdp(),
+    ));
+    Ok(())
+}
+
+async fn set_remote_description(
+    conn: &RtcPeerConnection,
+    sdp_type: RtcSdpType,
+    sdp: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut remote_description = RtcSessionDescriptionInit::new(sdp_type);
+    remote_description.sdp(sdp);
+    JsFuture::from(conn.set_remote_description(&remote_description))
         .await
         .efix()?;
-    }
-
-    // select for channel ready or ice candidates
-    debug!("waiting for data channel to open");
-    loop {
-        select! {
-            _ = channel_ready_rx.next() => {
-                debug!("channel ready");
-                break;
-            }
-            msg = signal_receiver.next() => {
-                if let Some(PeerSignal::IceCandidate(candidate)) = msg {
-                    debug!("got an IceCandidate signal! {}", candidate);
-                    let mut ice_candidate = RtcIceCandidateInit::new(&candidate);
-                    ice_candidate.sdp_m_line_index(Some(0));
-                    JsFuture::from(
-                        conn.add_ice_candidate_with_opt_rtc_ice_candidate_init(Some(&ice_candidate)),
-                    )
-                    .await
-                    .efix()?;
-                }
-            }
-        };
-    }
-
-    // stop listening for ICE candidates
-    // TODO: we should support getting new ICE candidates even after connecting,
-    //       since it's possible to return to the ice gathering state
-    // See: <https://developer.mozilla.org/en-US/docs/Web/API/RTCPeerConnection/iceGatheringState>
-    conn.set_onicecandidate(None);
-
-    debug!("Ice completed: {:?}", conn.ice_gathering_state());
+    Ok(())
+}
 
-    Ok((signal_peer.id, data_channel))
+async fn add_ice_candidate(
+    conn: &RtcPeerConnection,
+    candidate: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut ice_candidate = RtcIceCandidateInit::new(candidate);
+    ice_candidate.sdp_m_line_index(Some(0));
+    JsFuture::from(conn.add_ice_candidate_with_opt_rtc_ice_candidate_init(Some(&ice_candidate)))
+        .await
+        .efix()?;
+    Ok(())
 }
 
-fn create_rtc_peer_connection(config: &WebRtcSocketConfig) -> RtcPeerConnection {
+/// Creates a peer connection, along with a receiver for its ICE connection
+/// state changes
+fn create_rtc_peer_connection(
+    config: &WebRtcSocketConfig,
+) -> Result<(RtcPeerConnection, UnboundedReceiver<RtcIceConnectionState>), Box<dyn std::error::Error>>
+{
     #[derive(Serialize)]
     struct IceServerConfig {
         urls: Vec<String>,
@@ -394,15 +755,57 @@ fn create_rtc_peer_connection(config: &WebRtcSocketConfig) -> RtcPeerConnection
     }
 
     let mut peer_config = RtcConfiguration::new();
-    let ice_server = &config.ice_server;
-    let ice_server_config = IceServerConfig {
-        urls: ice_server.urls.clone(),
-        username: ice_server.username.clone().unwrap_or_default(),
-        credential: ice_server.credential.clone().unwrap_or_default(),
-    };
-    let ice_server_config_list = [ice_server_config];
-    peer_config.ice_servers(&serde_wasm_bindgen::to_value(&ice_server_config_list).unwrap());
-    RtcPeerConnection::new_with_configuration(&peer_config).unwrap()
+    let ice_server_config_list: Vec<_> = config
+        .ice_servers
+        .iter()
+        .map(|ice_server| IceServerConfig {
+            urls: ice_server.urls.clone(),
+            username: ice_server.username.clone().unwrap_or_default(),
+            credential: ice_server.credential.clone().unwrap_or_default(),
+        })
+        .collect();
+    peer_config.ice_servers(&serde_wasm_bindgen::to_value(&ice_server_config_list)?);
+    let connection = RtcPeerConnection::new_with_configuration(&peer_config).efix()?;
+
+    let (ice_state_tx, ice_state_rx) = futures_channel::mpsc::unbounded();
+    let connection2 = connection.clone();
+    let onicestatechange: Box<dyn FnMut(JsValue)> = Box::new(move |_| {
+        let state = connection2.ice_connection_state();
+        debug!("Ice connection state has changed: {state:?}");
+        // Nobody cares anymore if the connection was discarded
+        let _ = ice_state_tx.unbounded_send(state);
+    });
+    let onicestatechange = Closure::wrap(onicestatechange);
+    connection.set_oniceconnectionstatechange(Some(onicestatechange.as_ref().unchecked_ref()));
+    onicestatechange.forget();
+
+    Ok((connection, ice_state_rx))
+}
+
+fn create_data_channels(
+    connection: RtcPeerConnection,
+    incoming_tx: Vec<futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>>,
+    peer_id: PeerId,
+    channel_ready: futures_channel::mpsc::Sender<u8>,
+    peer_disconnected_tx: UnboundedSender<PeerId>,
+    channel_configs: &[ChannelConfig],
+) -> Vec<RtcDataChannel> {
+    channel_configs
+        .iter()
+        .zip(incoming_tx)
+        .enumerate()
+        .map(|(i, (channel_config, incoming_tx))| {
+            create_data_channel(
+                connection.clone(),
+                incoming_tx,
+                peer_id.clone(),
+                channel_ready.clone(),
+                peer_disconnected_tx.clone(),
+                channel_config,
+                i,
+            )
+        })
+        .collect()
 }
 
 fn create_data_channel(
@@ -410,25 +813,35 @@ fn create_data_channel(
     incoming_tx: futures_channel::mpsc::UnboundedSender<(PeerId, Packet)>,
     peer_id: PeerId,
     mut channel_ready: futures_channel::mpsc::Sender<u8>,
+    peer_disconnected_tx: UnboundedSender<PeerId>,
+    channel_config: &ChannelConfig,
+    channel_index: usize,
 ) -> RtcDataChannel {
     let mut data_channel_config = RtcDataChannelInit::new();
-    data_channel_config.ordered(false);
-    data_channel_config.max_retransmits(0);
+    data_channel_config.ordered(channel_config.ordered);
+    if let Some(max_retransmits) = channel_config.max_retransmits {
+        data_channel_config.max_retransmits(max_retransmits);
+    }
+    if let Some(max_packet_lifetime) = channel_config.max_packet_lifetime {
+        data_channel_config.max_packet_life_time(max_packet_lifetime);
+    }
     data_channel_config.negotiated(true);
-    data_channel_config.id(DATA_CHANNEL_ID);
+    data_channel_config.id(DATA_CHANNEL_ID + channel_index as u16);
 
-    let channel =
-        connection.create_data_channel_with_data_channel_dict("webudp", &data_channel_config);
+    let channel = connection.create_data_channel_with_data_channel_dict(
+        &format!("matchbox_socket_{channel_index}"),
+        &data_channel_config,
+    );
     channel.set_binary_type(RtcDataChannelType::Arraybuffer);
 
+    let peer_id2 = peer_id.clone();
     let channel_onmsg_func: Box<dyn FnMut(MessageEvent)> = Box::new(move |event: MessageEvent| {
         debug!("incoming {:?}", event);
         if let Ok(arraybuf) = event.data().dyn_into::<js_sys::ArrayBuffer>() {
             let uarray = js_sys::Uint8Array::new(&arraybuf);
             let body = uarray.to_vec();
-            incoming_tx
-                .unbounded_send((peer_id.clone(), body.into_boxed_slice()))
-                .unwrap();
+            // The socket may have been dropped, in which case nobody cares
+            let _ = incoming_tx.unbounded_send((peer_id2.clone(), body.into_boxed_slice()));
         }
     });
     let channel_onmsg_closure = Closure::wrap(channel_onmsg_func);
@@ -437,21 +850,23 @@ fn create_data_channel(
 
     let channel_onopen_func: Box<dyn FnMut(JsValue)> = Box::new(move |_| {
         debug!("Rtc data channel opened :D :D");
-        channel_ready
-            .try_send(1)
-            .expect("failed to notify about open connection");
+        // The handshake may already have been abandoned
+        let _ = channel_ready.try_send(1);
     });
     let channel_onopen_closure = Closure::wrap(channel_onopen_func);
     channel.set_onopen(Some(channel_onopen_closure.as_ref().unchecked_ref()));
     channel_onopen_closure.forget();
 
-    channel
-}
+    let channel_onclose_func: Box<dyn FnMut(JsValue)> = Box::new(move |_| {
+        debug!("Rtc data channel closed");
+        // The message loop may already be gone if the socket was dropped
+        let _ = peer_disconnected_tx.unbounded_send(peer_id.clone());
+    });
+    let channel_onclose_closure = Closure::wrap(channel_onclose_func);
+    channel.set_onclose(Some(channel_onclose_closure.as_ref().unchecked_ref()));
+    channel_onclose_closure.forget();
 
-// Expect/unwrap is broken in select for some reason :/
-fn check(res: &Result<(PeerId, RtcDataChannel), Box<dyn std::error::Error>>) {
-    // but doing it inside a typed function works fine
-    res.as_ref().expect("handshake failed");
+    channel
 }
 
 // The bellow is just to wrap Result<JsValue, JsValue> into something sensible-ish
@@ -479,3 +894,88 @@ impl std::fmt::Display for JsError {
         write!(f, "{:?}", self.0)
     }
 }
+
+async fn peer_stats(connection: RtcPeerConnection) -> Result<PeerStats, JsValue> {
+    let report = JsFuture::from(connection.get_stats()).await?;
+    // RTCStatsReport is map-like, so it can be iterated like a Map
+    let mut reports = HashMap::new();
+    report
+        .unchecked_into::<js_sys::Map>()
+        .for_each(&mut |report, id| {
+            if let Some(id) = id.as_string() {
+                reports.insert(id, report);
+            }
+        });
+
+    let mut stats = PeerStats::default();
+    let mut selected_pair_id = None;
+    for report in reports.values() {
+        match get_string(report, "type").as_deref() {
+            Some("data-channel") => {
+                stats.bytes_sent += get_u64(report, "bytesSent").unwrap_or_default();
+                stats.bytes_received += get_u64(report, "bytesReceived").unwrap_or_default();
+                stats.packets_sent += get_u64(report, "messagesSent").unwrap_or_default();
+                stats.packets_received += get_u64(report, "messagesReceived").unwrap_or_default();
+            }
+            Some("transport") => {
+                selected_pair_id = get_string(report, "selectedCandidatePairId");
+            }
+            _ => {}
+        }
+    }
+
+    // Firefox doesn't report transports, but marks the selected pair instead
+    let selected_pair = selected_pair_id
+        .and_then(|id| reports.get(&id))
+        .or_else(|| {
+            reports.values().find(|report| {
+                get_string(report, "type").as_deref() == Some("candidate-pair")
+                    && get_bool(report, "selected").unwrap_or_default()
+            })
+        });
+
+    if let Some(pair) = selected_pair {
+        stats.round_trip_time = get_f64(pair, "currentRoundTripTime")
+            .filter(|rtt| *rtt >= 0.0)
+            .map(Duration::from_secs_f64);
+        stats.retransmissions = get_u64(pair, "retransmissionsSent");
+        stats.local_candidate_type = get_string(pair, "localCandidateId")
+            .and_then(|id| reports.get(&id))
+            .and_then(candidate_type);
+        stats.remote_candidate_type = get_string(pair, "remoteCandidateId")
+            .and_then(|id| reports.get(&id))
+            .and_then(candidate_type);
+    }
+
+    Ok(stats)
+}
+
+fn candidate_type(report: &JsValue) -> Option<CandidateType> {
+    match get_string(report, "candidateType")?.as_str() {
+        "host" => Some(CandidateType::Host),
+        "srflx" => Some(CandidateType::ServerReflexive),
+        "prflx" => Some(CandidateType::PeerReflexive),
+        "relay" => Some(CandidateType::Relay),
+        _ => None,
+    }
+}
+
+fn get_f64(report: &JsValue, key: &str) -> Option<f64> {
+    Reflect::get(report, &JsValue::from_str(key)).ok()?.as_f64()
+}
+
+fn get_u64(report: &JsValue, key: &str) -> Option<u64> {
+    get_f64(report, key).map(|value| value as u64)
+}
+
+fn get_bool(report: &JsValue, key: &str) -> Option<bool> {
+    Reflect::get(report, &JsValue::from_str(key))
+        .ok()?
+        .as_bool()
+}
+
+fn get_string(report: &JsValue, key: &str) -> Option<String> {
+    Reflect::get(report, &JsValue::from_str(key))
+        .ok()?
+        .as_string()
+}
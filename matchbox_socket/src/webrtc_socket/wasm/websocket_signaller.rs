@@ -0,0 +1,113 @@
+use crate::webrtc_socket::{
+    messages::*,
+    signaller::{connection_url, new_resume_token, with_query_param, Signaller, SignallerFuture},
+    SocketError,
+};
+use futures::{SinkExt, StreamExt};
+use log::{debug, error};
+use ws_stream_wasm::{WsMessage, WsMeta, WsStream};
+
+/// Signals with a matchbox server over a websocket
+#[derive(Debug)]
+pub struct WebSocketSignaller {
+    room_url: String,
+    auth_token: Option<String>,
+    /// Lets us keep our id when we reconnect
+    resume_token: String,
+    ws: Option<(WsMeta, WsStream)>,
+}
+
+impl WebSocketSignaller {
+    pub fn new(room_url: String, auth_token: Option<String>) -> Self {
+        Self {
+            room_url,
+            auth_token,
+            resume_token: new_resume_token(),
+            ws: None,
+        }
+    }
+
+    /// Browsers can't send custom headers with websockets, so the token goes
+    /// in the query instead
+    fn url(&self) -> String {
+        let url = connection_url(&self.room_url, &self.resume_token);
+        match &self.auth_token {
+            // Tokens are url-safe base64, so no escaping needed
+            Some(token) => with_query_param(&url, "token", token),
+            None => url,
+        }
+    }
+
+    fn wsio(&mut self) -> Result<&mut WsStream, SocketError> {
+        self.ws
+            .as_mut()
+            .map(|(_ws, wsio)| wsio)
+            .ok_or_else(|| SocketError::Signalling("not connected".to_string()))
+    }
+}
+
+impl Signaller for WebSocketSignaller {
+    fn connect(&mut self) -> SignallerFuture<'_, ()> {
+        Box::pin(async move {
+            // Drop any previous connection first
+            self.ws = None;
+            let ws = WsMeta::connect(&self.url(), None)
+                .await
+                .map_err(|e| SocketError::ConnectionFailed(e.to_string()))?;
+            self.ws = Some(ws);
+            Ok(())
+        })
+    }
+
+    fn send(&mut self, request: PeerRequest) -> SignallerFuture<'_, ()> {
+        Box::pin(async move {
+            let request = serde_json::to_string(&request).expect("serializing request");
+            debug!("-> {}", request);
+            self.wsio()?
+                .send(WsMessage::Text(request))
+                .await
+                .map_err(|e| SocketError::Signalling(e.to_string()))
+        })
+    }
+
+    fn next_event(&mut self) -> SignallerFuture<'_, PeerEvent> {
+        Box::pin(async move {
+            let wsio = self.wsio()?;
+            loop {
+                match wsio.next().await {
+                    Some(WsMessage::Text(message)) => {
+                        debug!("{}", message);
+                        return serde_json::from_str(&message).map_err(|e| {
+                            SocketError::Signalling(format!(
+                                "couldn't parse peer event: {}.\nEvent: {}",
+                                e, message
+                            ))
+                        });
+                    }
+                    Some(WsMessage::Binary(_)) => {
+                        error!(
+                            "Received binary data from signal server (expected text). Ignoring."
+                        );
+                    }
+                    None => {
+                        error!("Disconnected from signalling server!");
+                        return Err(SocketError::Signalling(
+                            "connection closed by signalling server".to_string(),
+                        ));
+                    }
+                }
+            }
+        })
+    }
+
+    fn close(&mut self) -> SignallerFuture<'_, ()> {
+        Box::pin(async move {
+            if let Some((ws, _wsio)) = self.ws.take() {
+                ws.close()
+                    .await
+                    .map_err(|e| SocketError::Signalling(e.to_string()))?;
+            }
+            Ok(())
+        })
+    }
+}